@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Blending of offsets near the bottom (flat bottom) and the top (flat top) of the model.
+//!
+//! The offset is scaled by strengths which rise from 0 at the bed to 1 at height h_b,
+//! and fall from 1 at h_t below the top to 0 at the top:
+//!   z' = z + s(z/h_b)*s((top - z)/h_t)*offset
+
+use std::f64::consts::PI;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{solve_monotonic, solve_monotonic_unbounded};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, ValueEnum)]
+pub enum BlendProfile {
+    /// s(t) = t
+    #[default]
+    Linear,
+    /// s(t) = 3t^2 - 2t^3
+    Smoothstep,
+    /// s(t) = (1 - cos(πt))/2
+    Cosine,
+    /// s(t) = ψ(t)/(ψ(t) + ψ(1-t)) where ψ(t) = exp(-1/t) (smooth at both ends)
+    Exponential,
+}
+
+impl BlendProfile {
+    fn strength(&self, t: f64) -> f64 {
+        if t <= 0.0 {
+            return self.strength_deriv(0.0) * t;
+        } else if t >= 1.0 {
+            return 1.0;
+        }
+
+        match self {
+            BlendProfile::Linear => t,
+            BlendProfile::Smoothstep => t * t * (3.0 - 2.0 * t),
+            BlendProfile::Cosine => (1.0 - (PI * t).cos()) / 2.0,
+            BlendProfile::Exponential => {
+                let (a, b) = ((-1.0 / t).exp(), (-1.0 / (1.0 - t)).exp());
+                a / (a + b)
+            }
+        }
+    }
+
+    /// Derivative of `strength`
+    fn strength_deriv(&self, t: f64) -> f64 {
+        let t = match self {
+            // Linear profile is extended below the bed
+            BlendProfile::Linear if t < 1.0 => return 1.0,
+            _ if t <= 0.0 || t >= 1.0 => return 0.0,
+            _ => t,
+        };
+
+        match self {
+            BlendProfile::Linear => unreachable!(),
+            BlendProfile::Smoothstep => 6.0 * t * (1.0 - t),
+            BlendProfile::Cosine => PI * (PI * t).sin() / 2.0,
+            BlendProfile::Exponential => {
+                let (a, b) = ((-1.0 / t).exp(), (-1.0 / (1.0 - t)).exp());
+                a * b * (1.0 / (t * t) + 1.0 / ((1.0 - t) * (1.0 - t))) / ((a + b) * (a + b))
+            }
+        }
+    }
+
+    /// Maximum of `strength_deriv`
+    fn max_strength_deriv(&self) -> f64 {
+        match self {
+            BlendProfile::Linear => 1.0,
+            BlendProfile::Smoothstep => 1.5,
+            BlendProfile::Cosine => PI / 2.0,
+            BlendProfile::Exponential => 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Blend {
+    pub flat_bottom: f64,
+    #[serde(default)]
+    pub blend_profile: BlendProfile,
+    #[serde(default)]
+    pub flat_top: f64,
+    /// Z coordinate of the top of the model
+    #[serde(default)]
+    pub top: f64,
+}
+
+impl Blend {
+    pub fn new(flat_bottom: f64, blend_profile: BlendProfile) -> Self {
+        Self {
+            flat_bottom,
+            blend_profile,
+            flat_top: 0.0,
+            top: 0.0,
+        }
+    }
+
+    pub fn with_flat_top(self, flat_top: f64, top: f64) -> Self {
+        Self {
+            flat_top,
+            top,
+            ..self
+        }
+    }
+
+    pub fn apply(&self, z: f64, offset: f64) -> f64 {
+        z + self.strength(z, offset) * offset
+    }
+
+    pub fn apply_inverse(&self, z: f64, offset: f64) -> Result<f64> {
+        if self.flat_top != 0.0 {
+            return self.apply_inverse_numerical(z, offset);
+        }
+        if self.flat_bottom == 0.0 {
+            return Ok(z - offset);
+        }
+
+        let height = self.bottom_height(offset);
+        let warped_height = height + offset;
+        Ok(if z >= warped_height {
+            z - offset
+        } else if z <= 0.0 || self.blend_profile == BlendProfile::Linear {
+            // Linear part
+            z / (1.0 + self.blend_profile.strength_deriv(0.0) * offset / height)
+        } else {
+            solve_monotonic(
+                |z| (self.apply(z, offset), self.jacobian(z, offset)),
+                z,
+                0.0,
+                height,
+            )
+        })
+    }
+
+    /// Derivative of the warped z respect to the original z
+    pub fn jacobian(&self, z: f64, offset: f64) -> f64 {
+        let (bottom, bottom_deriv) = if self.flat_bottom != 0.0 {
+            let height = self.bottom_height(offset);
+            (
+                self.blend_profile.strength(z / height),
+                self.blend_profile.strength_deriv(z / height) / height,
+            )
+        } else {
+            (1.0, 0.0)
+        };
+
+        let (top, top_deriv) = if self.flat_top != 0.0 {
+            let height = self.top_height(offset);
+            let t = (self.top - z) / height;
+            if t > 0.0 {
+                (
+                    self.blend_profile.strength(t),
+                    -self.blend_profile.strength_deriv(t) / height,
+                )
+            } else {
+                (0.0, 0.0)
+            }
+        } else {
+            (1.0, 0.0)
+        };
+
+        1.0 + (bottom_deriv * top + bottom * top_deriv) * offset
+    }
+
+    /// Derivative of the warped z respect to the offset
+    /// (the heights of the blended regions also depend on the offset)
+    pub fn offset_deriv(&self, z: f64, offset: f64) -> f64 {
+        let max_deriv = self.blend_profile.max_strength_deriv();
+
+        let (bottom, bottom_deriv) = if self.flat_bottom != 0.0 {
+            let height = self.bottom_height(offset);
+            let height_deriv = if offset < 0.0 { -max_deriv } else { 0.0 };
+            let t = z / height;
+            (
+                self.blend_profile.strength(t),
+                -self.blend_profile.strength_deriv(t) * t / height * height_deriv,
+            )
+        } else {
+            (1.0, 0.0)
+        };
+
+        let (top, top_deriv) = if self.flat_top != 0.0 {
+            let height = self.top_height(offset);
+            let height_deriv = if offset > 0.0 { max_deriv } else { 0.0 };
+            let t = (self.top - z) / height;
+            if t > 0.0 {
+                (
+                    self.blend_profile.strength(t),
+                    -self.blend_profile.strength_deriv(t) * t / height * height_deriv,
+                )
+            } else {
+                (0.0, 0.0)
+            }
+        } else {
+            (1.0, 0.0)
+        };
+
+        bottom * top + (bottom_deriv * top + bottom * top_deriv) * offset
+    }
+
+    /// Product of strengths of flat bottom and flat top
+    fn strength(&self, z: f64, offset: f64) -> f64 {
+        let bottom = if self.flat_bottom != 0.0 {
+            self.blend_profile.strength(z / self.bottom_height(offset))
+        } else {
+            1.0
+        };
+
+        // Above the top, the offset is kept zero
+        let top = if self.flat_top != 0.0 {
+            let t = (self.top - z) / self.top_height(offset);
+            self.blend_profile.strength(t.max(0.0))
+        } else {
+            1.0
+        };
+
+        bottom * top
+    }
+
+    fn apply_inverse_numerical(&self, z: f64, offset: f64) -> Result<f64> {
+        solve_monotonic_unbounded(
+            |z| (self.apply(z, offset), self.jacobian(z, offset)),
+            z,
+            offset.abs().max(1.0),
+        )
+    }
+
+    /// Height of the blended region at the bottom.
+    /// For negative offset, the region is extended to keep the blend monotonic.
+    fn bottom_height(&self, offset: f64) -> f64 {
+        self.flat_bottom + self.blend_profile.max_strength_deriv() * (-offset).max(0.0)
+    }
+
+    /// Height of the blended region at the top.
+    /// For positive offset, the region is extended to keep the blend monotonic.
+    fn top_height(&self, offset: f64) -> f64 {
+        self.flat_top + self.blend_profile.max_strength_deriv() * offset.max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        for blend_profile in BlendProfile::value_variants() {
+            let blend = Blend::new(2.0, *blend_profile);
+
+            for offset in [-10.0, -1.0, 0.5, 10.0] {
+                // Bottom stays on the bed
+                assert_eq!(blend.apply(0.0, offset), 0.0);
+
+                for z in [-0.5, 0.3, 1.0, 1.9, 5.0, 20.0] {
+                    let warped = blend.apply(z, offset);
+                    assert!((blend.apply_inverse(warped, offset).unwrap() - z).abs() < 1e-9);
+
+                    let h = 1e-6;
+                    let numerical =
+                        (blend.apply(z + h, offset) - blend.apply(z - h, offset)) / (2.0 * h);
+                    assert!(blend.jacobian(z, offset) > 0.0);
+                    assert!((blend.jacobian(z, offset) - numerical).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flat_top() {
+        for blend_profile in BlendProfile::value_variants() {
+            let blend = Blend::new(2.0, *blend_profile).with_flat_top(3.0, 20.0);
+
+            for offset in [-10.0, -1.0, 0.5, 10.0] {
+                // Top stays planar
+                assert_eq!(blend.apply(20.0, offset), 20.0);
+
+                for z in [-0.5, 1.0, 5.0, 11.0, 18.0, 19.9, 25.0] {
+                    let warped = blend.apply(z, offset);
+                    assert!((blend.apply_inverse(warped, offset).unwrap() - z).abs() < 1e-9);
+
+                    let h = 1e-6;
+                    let numerical =
+                        (blend.apply(z + h, offset) - blend.apply(z - h, offset)) / (2.0 * h);
+                    assert!(blend.jacobian(z, offset) > 0.0);
+                    assert!((blend.jacobian(z, offset) - numerical).abs() < 1e-5);
+
+                    let numerical =
+                        (blend.apply(z, offset + h) - blend.apply(z, offset - h)) / (2.0 * h);
+                    assert!((blend.offset_deriv(z, offset) - numerical).abs() < 1e-5);
+                }
+            }
+        }
+    }
+}
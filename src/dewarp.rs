@@ -5,13 +5,13 @@
 use std::{
     ffi::OsString,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{BufRead, BufReader, BufWriter, Seek, Write},
     path::Path,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Args;
-use na::{vector, Vector3, Vector4};
+use na::{vector, Vector2, Vector3, Vector4};
 use nalgebra as na;
 
 use crate::gcode::command::BEGIN_DEWARP;
@@ -21,9 +21,11 @@ use crate::{
         parser::parse_line,
     },
     transform::{Transform, TransformData},
+    utils::Aabb,
 };
 
 const DEFAULT_MAX_LINE_LEN: f64 = 1.0; // 1 mm
+const DEFAULT_PLACEMENT_TOLERANCE: f64 = 1.0; // 1 mm
 
 #[derive(Args)]
 pub struct DewarpArgs {
@@ -33,18 +35,36 @@ pub struct DewarpArgs {
     max_line_len: f64,
     #[arg(short, long)]
     output_file: Option<OsString>,
+    /// Output nozzle rotation to C axis for rotating tilted nozzle (4-axis) printers
+    #[arg(long)]
+    rtn: bool,
+    /// Allowed difference between the extrusion bounding box and the warped model
+    #[arg(long, default_value_t = DEFAULT_PLACEMENT_TOLERANCE)]
+    placement_tolerance: f64,
 }
 
 pub fn command_main(args: DewarpArgs) -> Result<()> {
     let input_path = Path::new(&args.input_file);
-    let input_file = File::open(input_path)?;
+    let mut input_file = File::open(input_path)?;
 
     let transform_file_path = Path::new(&args.transform_file);
     let transform_file = File::open(transform_file_path)?;
     let TransformData {
         transform,
         warped_aabb,
+        center: original_center,
+        original_aabb,
+        ..
     } = serde_json::de::from_reader(transform_file)?;
+    transform.validate()?;
+
+    // First pass only finds the placement, then the file is read again for dewarping
+    let placed_center = detect_center(
+        BufReader::new(&input_file),
+        &warped_aabb,
+        args.placement_tolerance,
+    )?;
+    input_file.rewind()?;
 
     let mut default_output_path = input_path.to_owned();
     default_output_path.set_extension("dewarped.gcode");
@@ -54,30 +74,59 @@ pub fn command_main(args: DewarpArgs) -> Result<()> {
             .unwrap_or(default_output_path.as_os_str().to_owned()),
     )?;
 
-    dewarp_gcode(
+    let footprint = dewarp_gcode(
         input_file,
         output_file,
-        transform,
+        &transform,
         args.max_line_len,
         warped_aabb.origin.z,
+        args.rtn,
+        placed_center,
     )?;
 
+    // Dewarped extrusions should cover the original model, unless the transform file is for another model
+    if let (Some(original_center), Some(original_aabb), Some((min, max))) =
+        (original_center, original_aabb, footprint)
+    {
+        if !footprint_matches(
+            (min, max),
+            original_center,
+            &original_aabb,
+            args.placement_tolerance,
+        ) {
+            eprintln!(
+                "Warning: Dewarped extrusions ({:.3} x {:.3} mm) do not match the original model ({:.3} x {:.3} mm). \
+                 The transform file may belong to another model.",
+                max.x - min.x,
+                max.y - min.y,
+                original_aabb.size.x,
+                original_aabb.size.y
+            );
+        }
+    }
+
     Ok(())
 }
 
+/// Dewarp the G-code, returning the bounding box of the dewarped extrusions relative to the transform center
 fn dewarp_gcode(
     input_file: File,
     output_file: File,
-    transform: Transform,
+    transform: &Transform,
     max_line_len: f64,
     z_offset: f64,
-) -> Result<()> {
+    rtn: bool,
+    placed_center: Option<Vector3<f64>>,
+) -> Result<Option<(Vector2<f64>, Vector2<f64>)>> {
     let mut writer = BufWriter::new(output_file);
+    let mut min = Vector2::from_element(f64::MAX);
+    let mut max = Vector2::from_element(f64::MIN);
 
     let mut enabled = false;
     let mut center = Vector3::zeros();
     let mut last_pos = Vector4::zeros();
     let mut corrected_e = 0.0;
+    let mut nozzle_rotation = 0.0;
 
     for line in BufReader::new(input_file).lines() {
         let line = line?;
@@ -98,12 +147,24 @@ fn dewarp_gcode(
                         let mut last_e = last_pos[3];
                         // Split movement into short parts because it may be nonlinear after dewarping
                         for p in interpolate(&last_pos, &pos, max_line_len) {
-                            let dewarped = dewarp_point(p.xyz(), transform, center);
+                            let dewarped = dewarp_point(p.xyz(), transform, center)?;
                             // Correct extrusion length using the inverse of Jacobian determinant
-                            corrected_e += (p[3] - last_e) / extrusion_correction(p.xyz(), transform, center);
+                            corrected_e += (p[3] - last_e) / extrusion_correction(p.xyz(), transform, center)?;
+                            if p[3] > last_e {
+                                let relative = (dewarped - center).xy();
+                                min = min.inf(&relative);
+                                max = max.sup(&relative);
+                            }
                             last_e = p[3];
 
                             let z = dewarped.z.max(0.0); // Workaround for initial moves
+                            let c = if rtn {
+                                nozzle_rotation =
+                                    nozzle_angle(p.xyz(), transform, center, nozzle_rotation)?;
+                                Some(nozzle_rotation)
+                            } else {
+                                None
+                            };
                             match cmd {
                                 Command::G0(ref cmd) => writeln!(
                                     &mut writer,
@@ -112,10 +173,10 @@ fn dewarp_gcode(
                                         x: Some(dewarped.x),
                                         y: Some(dewarped.y),
                                         z: Some(z),
+                                        c: c.or(cmd.c),
                                         e: Some(corrected_e),
                                         ..cmd.clone()
                                     })
-                                    .to_string()
                                 )?,
                                 Command::G1(ref cmd) => writeln!(
                                     &mut writer,
@@ -124,10 +185,10 @@ fn dewarp_gcode(
                                         x: Some(dewarped.x),
                                         y: Some(dewarped.y),
                                         z: Some(z),
+                                        c: c.or(cmd.c),
                                         e: Some(corrected_e),
                                         ..cmd.clone()
                                     })
-                                    .to_string()
                                 )?,
                                 _ => unreachable!()
                             }
@@ -158,7 +219,19 @@ fn dewarp_gcode(
                 }
                 Command::BEGIN_DEWARP(BEGIN_DEWARP { x, y }) => {
                     enabled = true;
-                    center = vector![x.unwrap_or(0.0) / 2.0, y.unwrap_or(0.0) / 2.0, 0.0];
+                    let bed_center = vector![x.unwrap_or(0.0) / 2.0, y.unwrap_or(0.0) / 2.0, 0.0];
+                    center = match placed_center {
+                        Some(placed_center) => {
+                            if (placed_center - bed_center).norm() > 1e-3 {
+                                eprintln!(
+                                    "The model is not placed at the bed center. Using the detected center X{:.3} Y{:.3} instead of X{:.3} Y{:.3}.",
+                                    placed_center.x, placed_center.y, bed_center.x, bed_center.y
+                                );
+                            }
+                            placed_center
+                        }
+                        None => bed_center,
+                    };
                 }
                 Command::END_DEWARP(_) => {
                     enabled = false;
@@ -170,15 +243,122 @@ fn dewarp_gcode(
         }
     }
 
-    Ok(())
+    Ok((min.x <= max.x).then_some((min, max)))
+}
+
+/// Find where the slicer placed the transform center,
+/// by comparing the bounding box of extrusions with the warped model
+fn detect_center(
+    reader: impl BufRead,
+    warped_aabb: &Aabb,
+    tolerance: f64,
+) -> Result<Option<Vector3<f64>>> {
+    let mut enabled = false;
+    let mut last_pos = Vector2::zeros();
+    let mut last_e = 0.0;
+    let mut min = Vector2::from_element(f64::MAX);
+    let mut max = Vector2::from_element(f64::MIN);
+
+    for line in reader.lines() {
+        match parse_line(&line?) {
+            Ok((_, Some(Command::G0(G0 { x, y, e, .. }))))
+            | Ok((_, Some(Command::G1(G1 { x, y, e, .. })))) => {
+                let pos = vector![x.unwrap_or(last_pos.x), y.unwrap_or(last_pos.y)];
+                let e = e.unwrap_or(last_e);
+                if enabled && e > last_e {
+                    min = min.inf(&last_pos).inf(&pos);
+                    max = max.sup(&last_pos).sup(&pos);
+                }
+                last_pos = pos;
+                last_e = e;
+            }
+            Ok((_, Some(Command::G92(G92 { e: Some(e), .. })))) => last_e = e,
+            Ok((_, Some(Command::BEGIN_DEWARP(_)))) => enabled = true,
+            Ok((_, Some(Command::END_DEWARP(_)))) => enabled = false,
+            _ => (),
+        }
+    }
+
+    if min.x > max.x {
+        // No extrusion
+        return Ok(None);
+    }
+
+    let size = max - min;
+    let expected_size = warped_aabb.size.xy();
+    if (size - expected_size).abs().max() > tolerance {
+        bail!(
+            "Size of the printed region ({:.3} x {:.3} mm) does not match the warped model ({:.3} x {:.3} mm). \
+             The model may have been scaled or rotated in the slicer, or supports, brims or skirts may be enabled.",
+            size.x,
+            size.y,
+            expected_size.x,
+            expected_size.y
+        );
+    }
+
+    // Warped model is expressed relative to the transform center
+    let center = (min + max) / 2.0 - (warped_aabb.origin.xy() + expected_size / 2.0);
+    Ok(Some(vector![center.x, center.y, 0.0]))
 }
 
-fn dewarp_point(point: Vector3<f64>, transform: Transform, center: Vector3<f64>) -> Vector3<f64> {
-    transform.apply_inverse(point - center) + center
+/// Whether the bounding box of dewarped extrusions (relative to the transform center)
+/// matches the original model within `tolerance`
+fn footprint_matches(
+    footprint: (Vector2<f64>, Vector2<f64>),
+    original_center: Vector3<f64>,
+    original_aabb: &Aabb,
+    tolerance: f64,
+) -> bool {
+    let (min, max) = footprint;
+    let expected_min = (original_aabb.origin - original_center).xy();
+    let expected_max = expected_min + original_aabb.size.xy();
+    (min - expected_min)
+        .abs()
+        .sup(&(max - expected_max).abs())
+        .max()
+        <= tolerance
 }
 
-fn extrusion_correction(point: Vector3<f64>, transform: Transform, center: Vector3<f64>) -> f64 {
-    transform.jacobian(transform.apply_inverse(point - center))
+fn dewarp_point(
+    point: Vector3<f64>,
+    transform: &Transform,
+    center: Vector3<f64>,
+) -> Result<Vector3<f64>> {
+    let dewarped = transform.apply_inverse(point - center).with_context(|| {
+        format!(
+            "Could not dewarp X{:.3} Y{:.3} Z{:.3}",
+            point.x, point.y, point.z
+        )
+    })?;
+    Ok(dewarped + center)
+}
+
+fn extrusion_correction(
+    point: Vector3<f64>,
+    transform: &Transform,
+    center: Vector3<f64>,
+) -> Result<f64> {
+    Ok(transform.jacobian(transform.apply_inverse(point - center)?))
+}
+
+/// Direction (in degrees, counterclockwise from X axis) in which the layer surface descends.
+/// A tilted nozzle facing this direction does not collide with the layer being printed.
+fn nozzle_angle(
+    point: Vector3<f64>,
+    transform: &Transform,
+    center: Vector3<f64>,
+    last_angle: f64,
+) -> Result<f64> {
+    let gradient = transform.offset_gradient(transform.apply_inverse(point - center)?);
+    if gradient.norm() < 1e-9 {
+        // Direction is undefined (e.g. at the apex of a cone)
+        return Ok(last_angle);
+    }
+
+    let angle = gradient.y.atan2(gradient.x).to_degrees();
+    // Avoid unnecessary full rotation by choosing the nearest equivalent angle
+    Ok(angle + 360.0 * ((last_angle - angle) / 360.0).round())
 }
 
 fn interpolate(from: &Vector4<f64>, to: &Vector4<f64>, max_step: f64) -> Vec<Vector4<f64>> {
@@ -188,7 +368,95 @@ fn interpolate(from: &Vector4<f64>, to: &Vector4<f64>, max_step: f64) -> Vec<Vec
     (1..=div)
         .map(move |i| {
             let t = (i as f64) / (div as f64);
-            from.lerp(&to, t)
+            from.lerp(to, t)
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Warped model of 10x10 mm around the transform center
+    const WARPED_AABB: Aabb = Aabb {
+        origin: Vector3::new(-5.0, -5.0, 0.0),
+        size: Vector3::new(10.0, 10.0, 12.0),
+    };
+
+    /// Square perimeter extruded between the corners, preceded by a travel move far away
+    fn square(min: (f64, f64), max: (f64, f64)) -> String {
+        format!(
+            "G1 X0 Y0 Z0.2 F3000\n\
+             BEGIN_DEWARP X200 Y200\n\
+             G1 X{0} Y{1}\n\
+             G1 X{2} Y{1} E1\n\
+             G1 X{2} Y{3} E2\n\
+             G1 X{0} Y{3} E3\n\
+             G1 X{0} Y{1} E4\n\
+             END_DEWARP\n\
+             G1 X300 Y300 E5\n",
+            min.0, min.1, max.0, max.1
+        )
+    }
+
+    #[test]
+    fn detect_center() {
+        // Placed off the bed center
+        let gcode = square((115.0, 95.0), (125.0, 105.0));
+        let center = super::detect_center(gcode.as_bytes(), &WARPED_AABB, 1.0).unwrap();
+        assert_eq!(center, Some(vector![120.0, 100.0, 0.0]));
+
+        // Scaled in the slicer
+        let gcode = square((90.0, 95.0), (110.0, 105.0));
+        assert!(super::detect_center(gcode.as_bytes(), &WARPED_AABB, 1.0).is_err());
+
+        // No extrusion
+        let gcode = "BEGIN_DEWARP X200 Y200\nG1 X10 Y10\nEND_DEWARP\n";
+        let center = super::detect_center(gcode.as_bytes(), &WARPED_AABB, 1.0).unwrap();
+        assert_eq!(center, None);
+    }
+
+    #[test]
+    fn footprint_matches() {
+        // Original model of 10x20 mm, whose transform center is at the middle of its bottom
+        let original_aabb = Aabb {
+            origin: vector![10.0, 20.0, 0.0],
+            size: vector![10.0, 20.0, 5.0],
+        };
+        let original_center = vector![15.0, 30.0, 0.0];
+
+        let matches =
+            |footprint| super::footprint_matches(footprint, original_center, &original_aabb, 1.0);
+
+        // Extrusions are inside the model by half the line width
+        assert!(matches((vector![-4.8, -9.8], vector![4.8, 9.8])));
+        assert!(!matches((vector![-4.8, -4.8], vector![4.8, 4.8])));
+    }
+    #[test]
+    fn nozzle_angle() {
+        let center = vector![100.0, 100.0, 0.0];
+        let cone = Transform::Conical {
+            slope_angle: 0.5,
+            apex_radius: 0.0,
+            anisotropy: Default::default(),
+            blend: Default::default(),
+        };
+        let angle = |transform: &Transform, x: f64, y: f64, last_angle: f64| {
+            super::nozzle_angle(vector![x, y, 5.0], transform, center, last_angle).unwrap()
+        };
+
+        // Layers of a positive cone descend outward
+        assert!(angle(&cone, 110.0, 100.0, 0.0).abs() < 1e-9);
+        assert!((angle(&cone, 100.0, 110.0, 0.0) - 90.0).abs() < 1e-9);
+        assert!((angle(&cone, 90.0, 100.0, 170.0) - 180.0).abs() < 1e-9);
+        // Nearest to the last angle, without a full rotation
+        assert!((angle(&cone, 110.0, 100.0, 350.0) - 360.0).abs() < 1e-9);
+        assert!((angle(&cone, 100.0, 90.0, 0.0) + 90.0).abs() < 1e-9);
+        // Undefined at the apex
+        assert_eq!(angle(&cone, 100.0, 100.0, 42.0), 42.0);
+
+        // Bent layers descend toward the center
+        let bend = Transform::Bend { radius: 40.0 };
+        assert!((angle(&bend, 110.0, 100.0, 180.0) - 180.0).abs() < 1e-9);
+    }
+}
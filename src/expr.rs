@@ -0,0 +1,324 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Math expressions for user-defined offsets.
+//!
+//! Supported syntax:
+//! - Operators: `+`, `-`, `*`, `/`, `^` (power) and parentheses
+//! - Variables: `x`, `y`, `z`
+//! - Constants: `pi`, `e`
+//! - Functions: `sin`, `cos`, `tan`, `asin`, `acos`, `atan`, `atan2`, `sinh`, `cosh`, `tanh`,
+//!   `sqrt`, `abs`, `exp`, `ln`, `log10`, `hypot`, `min`, `max`, `pow`
+
+use anyhow::{anyhow, bail, Result};
+use nom::{
+    branch::alt,
+    character::complete::{alpha1, alphanumeric0, char, multispace0, one_of},
+    combinator::{all_consuming, map, map_res, recognize},
+    multi::{many0, separated_list1},
+    number::complete::recognize_float,
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+use serde::{Deserialize, Serialize};
+
+/// Parsed expression. Serialized as its source text.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(f64),
+    X,
+    Y,
+    Z,
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Sinh,
+    Cosh,
+    Tanh,
+    Sqrt,
+    Abs,
+    Exp,
+    Ln,
+    Log10,
+    Hypot,
+    Min,
+    Max,
+    Pow,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self> {
+        let (_, root) = all_consuming(delimited(multispace0, parse_expr, multispace0))(source)
+            .map_err(|e| anyhow!("Invalid expression \"{}\": {}", source, e))?;
+        root.validate()?;
+
+        Ok(Self {
+            source: source.to_owned(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.root.eval(x, y, z)
+    }
+
+    /// Whether `z` appears in the expression
+    pub fn depends_on_z(&self) -> bool {
+        self.root.depends_on_z()
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::parse(&value)
+    }
+}
+
+impl From<Expr> for String {
+    fn from(value: Expr) -> Self {
+        value.source
+    }
+}
+
+impl Node {
+    fn eval(&self, x: f64, y: f64, z: f64) -> f64 {
+        match self {
+            Node::Number(value) => *value,
+            Node::X => x,
+            Node::Y => y,
+            Node::Z => z,
+            Node::Neg(arg) => -arg.eval(x, y, z),
+            Node::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(x, y, z), rhs.eval(x, y, z));
+                match op {
+                    '+' => lhs + rhs,
+                    '-' => lhs - rhs,
+                    '*' => lhs * rhs,
+                    '/' => lhs / rhs,
+                    '^' => lhs.powf(rhs),
+                    _ => unreachable!(),
+                }
+            }
+            Node::Call(func, args) => {
+                let a = args[0].eval(x, y, z);
+                let b = || args[1].eval(x, y, z);
+                match func {
+                    Func::Sin => a.sin(),
+                    Func::Cos => a.cos(),
+                    Func::Tan => a.tan(),
+                    Func::Asin => a.asin(),
+                    Func::Acos => a.acos(),
+                    Func::Atan => a.atan(),
+                    Func::Atan2 => a.atan2(b()),
+                    Func::Sinh => a.sinh(),
+                    Func::Cosh => a.cosh(),
+                    Func::Tanh => a.tanh(),
+                    Func::Sqrt => a.sqrt(),
+                    Func::Abs => a.abs(),
+                    Func::Exp => a.exp(),
+                    Func::Ln => a.ln(),
+                    Func::Log10 => a.log10(),
+                    Func::Hypot => a.hypot(b()),
+                    Func::Min => a.min(b()),
+                    Func::Max => a.max(b()),
+                    Func::Pow => a.powf(b()),
+                }
+            }
+        }
+    }
+
+    fn depends_on_z(&self) -> bool {
+        match self {
+            Node::Z => true,
+            Node::Number(_) | Node::X | Node::Y => false,
+            Node::Neg(arg) => arg.depends_on_z(),
+            Node::Binary(_, lhs, rhs) => lhs.depends_on_z() || rhs.depends_on_z(),
+            Node::Call(_, args) => args.iter().any(Node::depends_on_z),
+        }
+    }
+
+    /// Check number of function arguments
+    fn validate(&self) -> Result<()> {
+        match self {
+            Node::Number(_) | Node::X | Node::Y | Node::Z => Ok(()),
+            Node::Neg(arg) => arg.validate(),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.validate()?;
+                rhs.validate()
+            }
+            Node::Call(func, args) => {
+                let arity = match func {
+                    Func::Atan2 | Func::Hypot | Func::Min | Func::Max | Func::Pow => 2,
+                    _ => 1,
+                };
+                if args.len() != arity {
+                    bail!(
+                        "{} takes {} argument(s) but {} given",
+                        format!("{:?}", func).to_lowercase(),
+                        arity,
+                        args.len()
+                    );
+                }
+                args.iter().try_for_each(Node::validate)
+            }
+        }
+    }
+}
+
+fn parse_expr(input: &str) -> IResult<&str, Node> {
+    parse_binary(input, &['+', '-'], parse_term)
+}
+
+fn parse_term(input: &str) -> IResult<&str, Node> {
+    parse_binary(input, &['*', '/'], parse_unary)
+}
+
+/// Left-associative chain of binary operators
+fn parse_binary<'a>(
+    input: &'a str,
+    ops: &[char],
+    operand: fn(&'a str) -> IResult<&'a str, Node>,
+) -> IResult<&'a str, Node> {
+    let (rest, first) = operand(input)?;
+    let (rest, others) = many0(pair(preceded(multispace0, one_of(ops)), operand))(rest)?;
+
+    Ok((
+        rest,
+        others.into_iter().fold(first, |lhs, (op, rhs)| {
+            Node::Binary(op, Box::new(lhs), Box::new(rhs))
+        }),
+    ))
+}
+
+fn parse_unary(input: &str) -> IResult<&str, Node> {
+    preceded(
+        multispace0,
+        alt((
+            map(preceded(char('-'), parse_unary), |arg| {
+                Node::Neg(Box::new(arg))
+            }),
+            preceded(char('+'), parse_unary),
+            parse_power,
+        )),
+    )(input)
+}
+
+/// Right-associative power operator
+fn parse_power(input: &str) -> IResult<&str, Node> {
+    let (rest, base) = parse_atom(input)?;
+
+    match preceded(pair(multispace0, char('^')), parse_unary)(rest) {
+        Ok((rest, exponent)) => Ok((rest, Node::Binary('^', Box::new(base), Box::new(exponent)))),
+        Err(_) => Ok((rest, base)),
+    }
+}
+
+fn parse_atom(input: &str) -> IResult<&str, Node> {
+    alt((
+        map_res(recognize_float, |s: &str| s.parse().map(Node::Number)),
+        delimited(char('('), parse_expr, preceded(multispace0, char(')'))),
+        parse_identifier,
+    ))(input)
+}
+
+fn parse_identifier(input: &str) -> IResult<&str, Node> {
+    let (rest, name) = recognize(pair(alpha1, alphanumeric0))(input)?;
+
+    // Function call
+    if let Ok((rest, args)) = delimited(
+        pair(multispace0, char('(')),
+        separated_list1(preceded(multispace0, char(',')), parse_expr),
+        preceded(multispace0, char(')')),
+    )(rest)
+    {
+        let func = match name {
+            "sin" => Func::Sin,
+            "cos" => Func::Cos,
+            "tan" => Func::Tan,
+            "asin" => Func::Asin,
+            "acos" => Func::Acos,
+            "atan" => Func::Atan,
+            "atan2" => Func::Atan2,
+            "sinh" => Func::Sinh,
+            "cosh" => Func::Cosh,
+            "tanh" => Func::Tanh,
+            "sqrt" => Func::Sqrt,
+            "abs" => Func::Abs,
+            "exp" => Func::Exp,
+            "ln" => Func::Ln,
+            "log10" => Func::Log10,
+            "hypot" => Func::Hypot,
+            "min" => Func::Min,
+            "max" => Func::Max,
+            "pow" => Func::Pow,
+            _ => return fail(input),
+        };
+        return Ok((rest, Node::Call(func, args)));
+    }
+
+    let node = match name {
+        "x" => Node::X,
+        "y" => Node::Y,
+        "z" => Node::Z,
+        "pi" => Node::Number(std::f64::consts::PI),
+        "e" => Node::Number(std::f64::consts::E),
+        _ => return fail(input),
+    };
+
+    Ok((rest, node))
+}
+
+fn fail<T>(input: &str) -> IResult<&str, T> {
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Tag,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval() {
+        let expr = Expr::parse("0.5*sin(x/3) + 0.1*hypot(x, y)").unwrap();
+        assert!((expr.eval(3.0, 4.0, 0.0) - (0.5 * 1.0f64.sin() + 0.5)).abs() < 1e-12);
+        assert!(!expr.depends_on_z());
+        assert!(Expr::parse("sin(x + z)").unwrap().depends_on_z());
+
+        // Precedence and associativity
+        assert_eq!(Expr::parse("2^3^2").unwrap().eval(0.0, 0.0, 0.0), 512.0);
+        assert_eq!(Expr::parse("-2^2").unwrap().eval(0.0, 0.0, 0.0), -4.0);
+        assert_eq!(Expr::parse("8 - 2 - 1").unwrap().eval(0.0, 0.0, 0.0), 5.0);
+        assert_eq!(Expr::parse("1e1*x").unwrap().eval(2.0, 0.0, 0.0), 20.0);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Expr::parse("sin(x").is_err());
+        assert!(Expr::parse("foo(x)").is_err());
+        assert!(Expr::parse("w").is_err());
+        assert!(Expr::parse("hypot(x)").is_err());
+    }
+}
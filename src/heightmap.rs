@@ -0,0 +1,509 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Offset field sampled on a regular grid.
+//!
+//! Supported file formats:
+//! - PGM (`P2` and `P5`) and PNG grayscale images, scaled so that white is the given height
+//! - CSV grids of offsets in mm
+//!
+//! Height maps can also be sampled from a function or from the upper envelope of a mesh.
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{bail, ensure, Context, Error, Result};
+use clap::ValueEnum;
+use na::{vector, Vector2, Vector3};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Mesh;
+
+/// Tolerance of barycentric and grid coordinates when sampling a mesh
+const EDGE_TOLERANCE: f64 = 1e-9;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, ValueEnum)]
+pub enum Interpolation {
+    Bilinear,
+    Bicubic,
+}
+
+/// Grid of offsets centered on the transform center.
+/// The first row is at the largest Y, same as images.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "HeightMapData")]
+pub struct HeightMap {
+    pub columns: usize,
+    pub rows: usize,
+    /// Physical size of the grid in mm
+    pub size: Vector2<f64>,
+    /// Offsets in mm (row-major)
+    pub values: Vec<f64>,
+    pub interpolation: Interpolation,
+}
+
+/// Unchecked height map as written in JSON
+#[derive(Deserialize)]
+struct HeightMapData {
+    columns: usize,
+    rows: usize,
+    size: Vector2<f64>,
+    values: Vec<f64>,
+    interpolation: Interpolation,
+}
+
+impl TryFrom<HeightMapData> for HeightMap {
+    type Error = Error;
+
+    fn try_from(data: HeightMapData) -> Result<Self> {
+        HeightMap::new(
+            data.columns,
+            data.rows,
+            data.size,
+            data.values,
+            data.interpolation,
+        )
+    }
+}
+
+impl HeightMap {
+    pub fn new(
+        columns: usize,
+        rows: usize,
+        size: Vector2<f64>,
+        values: Vec<f64>,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        ensure!(
+            columns >= 2 && rows >= 2,
+            "Height map must have at least 2x2 samples"
+        );
+        ensure!(
+            values.len() == columns * rows,
+            "Height map has {} samples but {}x{} grid is expected",
+            values.len(),
+            columns,
+            rows
+        );
+        ensure!(
+            size.x > 0.0 && size.y > 0.0,
+            "Height map size must be positive"
+        );
+
+        Ok(Self {
+            columns,
+            rows,
+            size,
+            values,
+            interpolation,
+        })
+    }
+
+    /// Load a height map file. Image brightness is scaled to `0..=height`.
+    pub fn load(
+        path: &Path,
+        size: Vector2<f64>,
+        height: f64,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        let (columns, rows, values) = match extension.as_deref() {
+            Some("pgm") => {
+                let (columns, rows, values) = read_pgm(&std::fs::read(path)?)?;
+                (columns, rows, values.iter().map(|v| v * height).collect())
+            }
+            Some("png") => {
+                let (columns, rows, values) = read_png(File::open(path)?)?;
+                (columns, rows, values.iter().map(|v| v * height).collect())
+            }
+            Some("csv") => read_csv(&std::fs::read_to_string(path)?)?,
+            _ => bail!("Unsupported height map format: {}", path.display()),
+        };
+
+        Self::new(columns, rows, size, values, interpolation)
+            .with_context(|| format!("Invalid height map: {}", path.display()))
+    }
+
+    /// Sample `f(x, y)` every `spacing` mm within ±`extent` mm
+    pub fn sampled(
+        extent: Vector2<f64>,
+        spacing: f64,
+        interpolation: Interpolation,
+        f: impl Fn(f64, f64) -> f64,
+    ) -> Result<Self> {
+        let mut map = Self::empty(extent, spacing, interpolation)?;
+        for j in 0..map.rows {
+            for i in 0..map.columns {
+                let p = map.position(i, j);
+                map.values[j * map.columns + i] = f(p.x, p.y);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Highest points of the mesh translated by `-center`, sampled every `spacing` mm within ±`extent` mm.
+    /// Where the mesh is absent, the values are extended from the nearest samples.
+    pub fn upper_envelope(
+        mesh: &Mesh,
+        center: Vector3<f64>,
+        extent: Vector2<f64>,
+        spacing: f64,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        let mut map = Self::empty(extent, spacing, interpolation)?;
+        let (columns, rows) = (map.columns, map.rows);
+        let mut values: Vec<Option<f64>> = vec![None; columns * rows];
+
+        for triangle in mesh.triangles.iter() {
+            let [a, b, c] = triangle.map(|i| mesh.vertices[i] - center);
+            let det = (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y);
+            if det.abs() < 1e-12 {
+                // Vertical faces are covered by their neighbors
+                continue;
+            }
+
+            // Grid points within the bounding box of the triangle
+            let (u0, v0) =
+                map.unclamped_grid_position(a.x.min(b.x).min(c.x), a.y.max(b.y).max(c.y));
+            let (u1, v1) =
+                map.unclamped_grid_position(a.x.max(b.x).max(c.x), a.y.min(b.y).min(c.y));
+            let range = |lo: f64, hi: f64, count: usize| {
+                let lo = (lo - EDGE_TOLERANCE).ceil().max(0.0) as usize;
+                let hi = (hi + EDGE_TOLERANCE).floor().min((count - 1) as f64) as usize;
+                lo..=hi
+            };
+            let (i_range, j_range) = (range(u0, u1, columns), range(v0, v1, rows));
+
+            for j in j_range {
+                for i in i_range.clone() {
+                    let p = map.position(i, j);
+                    // Barycentric coordinates
+                    let wb = ((p.x - a.x) * (c.y - a.y) - (c.x - a.x) * (p.y - a.y)) / det;
+                    let wc = ((b.x - a.x) * (p.y - a.y) - (p.x - a.x) * (b.y - a.y)) / det;
+                    let wa = 1.0 - wb - wc;
+                    // Points on shared edges must hit both triangles despite rounding errors
+                    if wa < -EDGE_TOLERANCE || wb < -EDGE_TOLERANCE || wc < -EDGE_TOLERANCE {
+                        continue;
+                    }
+                    let z = wa * a.z + wb * b.z + wc * c.z;
+                    let value = &mut values[j * columns + i];
+                    *value = Some(value.map_or(z, |v| v.max(z)));
+                }
+            }
+        }
+
+        ensure!(
+            values.iter().any(Option::is_some),
+            "Mesh does not cover any sample of the grid"
+        );
+
+        // Fill the gaps by averaging the filled neighbors, growing from the edges of the mesh
+        while values.iter().any(Option::is_none) {
+            let previous = values.clone();
+            for j in 0..rows {
+                for i in 0..columns {
+                    if previous[j * columns + i].is_some() {
+                        continue;
+                    }
+                    let neighbors: Vec<f64> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                        .iter()
+                        .filter_map(|&(di, dj)| {
+                            let (ni, nj) = (i as isize + di, j as isize + dj);
+                            if (0..columns as isize).contains(&ni)
+                                && (0..rows as isize).contains(&nj)
+                            {
+                                previous[nj as usize * columns + ni as usize]
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+                    if !neighbors.is_empty() {
+                        values[j * columns + i] =
+                            Some(neighbors.iter().sum::<f64>() / neighbors.len() as f64);
+                    }
+                }
+            }
+        }
+
+        map.values = values.into_iter().flatten().collect();
+        Ok(map)
+    }
+
+    /// Grid of zeros covering ±`extent` mm every `spacing` mm (or a little less)
+    fn empty(extent: Vector2<f64>, spacing: f64, interpolation: Interpolation) -> Result<Self> {
+        let columns = (2.0 * extent.x / spacing).ceil() as usize + 1;
+        let rows = (2.0 * extent.y / spacing).ceil() as usize + 1;
+        Self::new(
+            columns,
+            rows,
+            2.0 * extent,
+            vec![0.0; columns * rows],
+            interpolation,
+        )
+    }
+
+    pub fn sample(&self, x: f64, y: f64) -> f64 {
+        let (u, v) = self.grid_position(x, y);
+
+        match self.interpolation {
+            Interpolation::Bilinear => {
+                let (i, j) = (u.floor(), v.floor());
+                let (fu, fv) = (u - i, v - j);
+                let (i, j) = (i as isize, j as isize);
+
+                let top = lerp(self.at(i, j), self.at(i + 1, j), fu);
+                let bottom = lerp(self.at(i, j + 1), self.at(i + 1, j + 1), fu);
+                lerp(top, bottom, fv)
+            }
+            Interpolation::Bicubic => {
+                let (i, j) = (u.floor(), v.floor());
+                let (fu, fv) = (u - i, v - j);
+                let (i, j) = (i as isize, j as isize);
+
+                let rows = [-1, 0, 1, 2]
+                    .map(|dj| cubic([-1, 0, 1, 2].map(|di| self.at(i + di, j + dj)), fu));
+                cubic(rows, fv)
+            }
+        }
+    }
+
+    /// Continuous grid coordinates (column, row) of a point, clamped to the grid
+    fn grid_position(&self, x: f64, y: f64) -> (f64, f64) {
+        let (u, v) = self.unclamped_grid_position(x, y);
+
+        (
+            u.clamp(0.0, (self.columns - 1) as f64),
+            v.clamp(0.0, (self.rows - 1) as f64),
+        )
+    }
+
+    fn unclamped_grid_position(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x / self.size.x + 0.5) * ((self.columns - 1) as f64),
+            (0.5 - y / self.size.y) * ((self.rows - 1) as f64),
+        )
+    }
+
+    /// XY coordinates of a grid point
+    fn position(&self, i: usize, j: usize) -> Vector2<f64> {
+        vector![
+            (i as f64 / (self.columns - 1) as f64 - 0.5) * self.size.x,
+            (0.5 - j as f64 / (self.rows - 1) as f64) * self.size.y
+        ]
+    }
+
+    /// Value at a grid point. Out-of-range indices are clamped to the edge.
+    fn at(&self, i: isize, j: isize) -> f64 {
+        let i = i.clamp(0, self.columns as isize - 1) as usize;
+        let j = j.clamp(0, self.rows as isize - 1) as usize;
+        self.values[j * self.columns + i]
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+/// Catmull-Rom interpolation between `p[1]` and `p[2]`
+fn cubic(p: [f64; 4], t: f64) -> f64 {
+    let a = -0.5 * p[0] + 1.5 * p[1] - 1.5 * p[2] + 0.5 * p[3];
+    let b = p[0] - 2.5 * p[1] + 2.0 * p[2] - 0.5 * p[3];
+    let c = -0.5 * p[0] + 0.5 * p[2];
+    let d = p[1];
+
+    ((a * t + b) * t + c) * t + d
+}
+
+/// Read a PGM image as brightness values in `0..=1`
+fn read_pgm(data: &[u8]) -> Result<(usize, usize, Vec<f64>)> {
+    // Header consists of magic number, width, height and maxval separated by whitespaces and comments
+    let mut pos = 0;
+    let mut header = Vec::new();
+    while header.len() < 4 {
+        match data.get(pos) {
+            Some(b'#') => {
+                while data.get(pos).is_some_and(|&c| c != b'\n') {
+                    pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => pos += 1,
+            Some(_) => {
+                let start = pos;
+                while data.get(pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+                    pos += 1;
+                }
+                header.push(std::str::from_utf8(&data[start..pos])?);
+            }
+            None => bail!("Unexpected end of PGM header"),
+        }
+    }
+
+    let columns: usize = header[1].parse()?;
+    let rows: usize = header[2].parse()?;
+    let max_value: f64 = header[3].parse()?;
+    ensure!(max_value > 0.0, "Invalid PGM maxval");
+
+    let values: Vec<f64> = match header[0] {
+        "P2" => std::str::from_utf8(&data[pos..])?
+            .split_ascii_whitespace()
+            .map(|s| s.parse::<f64>().map(|v| v / max_value))
+            .collect::<Result<_, _>>()?,
+        "P5" => {
+            // Exactly one whitespace follows maxval
+            let pixels = &data[(pos + 1).min(data.len())..];
+            if max_value < 256.0 {
+                pixels.iter().map(|&v| (v as f64) / max_value).collect()
+            } else {
+                pixels
+                    .chunks_exact(2)
+                    .map(|v| (u16::from_be_bytes([v[0], v[1]]) as f64) / max_value)
+                    .collect()
+            }
+        }
+        magic => bail!("Unsupported PGM type: {}", magic),
+    };
+
+    ensure!(values.len() >= columns * rows, "PGM data is too short");
+
+    Ok((columns, rows, values[..columns * rows].to_vec()))
+}
+
+/// Read a PNG image as brightness values in `0..=1`
+fn read_png(file: File) -> Result<(usize, usize, Vec<f64>)> {
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let data = &buf[..info.buffer_size()];
+
+    let samples: Vec<f64> = match info.bit_depth {
+        png::BitDepth::Eight => data.iter().map(|&v| (v as f64) / 255.0).collect(),
+        png::BitDepth::Sixteen => data
+            .chunks_exact(2)
+            .map(|v| (u16::from_be_bytes([v[0], v[1]]) as f64) / 65535.0)
+            .collect(),
+        depth => bail!("Unsupported PNG bit depth: {:?}", depth),
+    };
+
+    let values = match info.color_type {
+        png::ColorType::Grayscale => samples,
+        png::ColorType::GrayscaleAlpha => samples.chunks_exact(2).map(|p| p[0]).collect(),
+        png::ColorType::Rgb | png::ColorType::Rgba => samples
+            .chunks_exact(info.color_type.samples())
+            // Rec. 709 luma
+            .map(|p| 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2])
+            .collect(),
+        color_type => bail!("Unsupported PNG color type: {:?}", color_type),
+    };
+
+    Ok((info.width as usize, info.height as usize, values))
+}
+
+/// Read comma-separated rows of offsets
+fn read_csv(text: &str) -> Result<(usize, usize, Vec<f64>)> {
+    let rows = text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.split(',')
+                .map(|e| e.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let columns = rows.first().map(|row| row.len()).unwrap_or(0);
+    ensure!(
+        rows.iter().all(|row| row.len() == columns),
+        "All rows of CSV height map must have the same length"
+    );
+
+    Ok((columns, rows.len(), rows.concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use na::vector;
+
+    #[test]
+    fn upper_envelope() {
+        // Two overlapping horizontal squares at different heights
+        let square = |z: f64, half: f64| {
+            [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                .map(|(x, y)| vector![x * half, y * half, z])
+        };
+        let mut vertices = square(1.0, 4.0).to_vec();
+        vertices.extend(square(3.0, 2.0));
+        let mesh = Mesh {
+            vertices,
+            triangles: vec![[0, 1, 2], [0, 2, 3], [4, 5, 6], [4, 6, 7]],
+        };
+
+        let map = HeightMap::upper_envelope(
+            &mesh,
+            vector![0.0, 0.0, 0.5],
+            vector![6.0, 6.0],
+            1.0,
+            Interpolation::Bilinear,
+        )
+        .unwrap();
+        assert_eq!((map.columns, map.rows), (13, 13));
+        assert_eq!(map.sample(0.0, 0.0), 2.5);
+        assert_eq!(map.sample(3.0, -3.0), 0.5);
+        // Extended outside the mesh
+        assert_eq!(map.sample(6.0, 0.0), 0.5);
+    }
+
+    #[test]
+    fn pgm() {
+        let (columns, rows, values) = read_pgm(b"P2\n# comment\n3 2\n4\n0 1 2\n3 4 4\n").unwrap();
+
+        assert_eq!((columns, rows), (3, 2));
+        assert_eq!(values, vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn sample() {
+        let (columns, rows, values) = read_csv("0, 1\n2, 3\n").unwrap();
+        let map = HeightMap::new(
+            columns,
+            rows,
+            vector![10.0, 10.0],
+            values,
+            Interpolation::Bilinear,
+        )
+        .unwrap();
+
+        // Corners (first row is +Y)
+        assert_eq!(map.sample(-5.0, 5.0), 0.0);
+        assert_eq!(map.sample(5.0, -5.0), 3.0);
+        assert_eq!(map.sample(0.0, 0.0), 1.5);
+        // Clamped outside
+        assert_eq!(map.sample(100.0, 100.0), 1.0);
+
+        // Bicubic interpolation passes through grid points
+        let map = HeightMap {
+            interpolation: Interpolation::Bicubic,
+            ..map
+        };
+        assert!((map.sample(-5.0, -5.0) - 2.0).abs() < 1e-12);
+
+        // Checked when loaded from JSON
+        let json = r#"{"columns": 3, "rows": 3, "size": [10, 10], "values": [0, 1], "interpolation": "Bilinear"}"#;
+        assert!(serde_json::from_str::<HeightMap>(json).is_err());
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HeightMap>(&json).unwrap().values,
+            map.values
+        );
+    }
+}
@@ -2,12 +2,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+mod blend;
 mod dewarp;
+mod expr;
 mod gcode;
+mod heightmap;
+mod mask;
+mod noise;
+mod profile;
+mod smoothing;
+mod surface;
 mod tessellation;
 mod transform;
 mod utils;
 mod warp;
+mod wave;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -23,7 +32,7 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Warp(WarpArgs),
+    Warp(Box<WarpArgs>),
     Dewarp(DewarpArgs),
 }
 
@@ -31,7 +40,7 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Warp(args) => warp::command_main(args),
+        Commands::Warp(args) => warp::command_main(*args),
         Commands::Dewarp(args) => dewarp::command_main(args),
     }
 }
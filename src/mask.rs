@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Regions in XY plane which limit where an offset is applied.
+
+use anyhow::{ensure, Result};
+use na::{vector, Vector2};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Region {
+    Circle { center: Vector2<f64>, radius: f64 },
+    Polygon(Vec<Vector2<f64>>),
+}
+
+/// Weight which is 1 inside the region and smoothly falls to 0 within `falloff` outside
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mask {
+    pub region: Region,
+    pub falloff: f64,
+}
+
+impl Region {
+    /// Distance from the boundary (negative inside) and its gradient
+    fn signed_distance(&self, point: Vector2<f64>) -> (f64, Vector2<f64>) {
+        match self {
+            Region::Circle { center, radius } => {
+                let diff = point - center;
+                let dist = diff.norm();
+                let gradient = if dist > 0.0 {
+                    diff / dist
+                } else {
+                    Vector2::zeros()
+                };
+                (dist - radius, gradient)
+            }
+            Region::Polygon(points) => {
+                let mut nearest = (f64::MAX, Vector2::zeros());
+                let mut inside = false;
+
+                for (i, &a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+
+                    // Nearest point on the edge
+                    let edge = b - a;
+                    let t = ((point - a).dot(&edge) / edge.norm_squared()).clamp(0.0, 1.0);
+                    let diff = point - (a + t * edge);
+                    let dist = diff.norm();
+                    if dist < nearest.0 {
+                        nearest = (dist, diff);
+                    }
+
+                    // Crossing number test
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) * edge.x / edge.y
+                    {
+                        inside = !inside;
+                    }
+                }
+
+                let (dist, diff) = nearest;
+                let gradient = if dist > 0.0 {
+                    diff / dist
+                } else {
+                    Vector2::zeros()
+                };
+                if inside {
+                    (-dist, -gradient)
+                } else {
+                    (dist, gradient)
+                }
+            }
+        }
+    }
+
+    pub fn translate(&self, offset: Vector2<f64>) -> Region {
+        match self {
+            Region::Circle { center, radius } => Region::Circle {
+                center: center + offset,
+                radius: *radius,
+            },
+            Region::Polygon(points) => Region::Polygon(points.iter().map(|p| p + offset).collect()),
+        }
+    }
+}
+
+impl Mask {
+    pub fn weight(&self, x: f64, y: f64) -> f64 {
+        let (dist, _) = self.region.signed_distance(vector![x, y]);
+        1.0 - smoothstep(self.falloff_position(dist))
+    }
+
+    pub fn weight_gradient(&self, x: f64, y: f64) -> Vector2<f64> {
+        let (dist, gradient) = self.region.signed_distance(vector![x, y]);
+        let t = self.falloff_position(dist);
+        if t <= 0.0 || t >= 1.0 {
+            return Vector2::zeros();
+        }
+        -6.0 * t * (1.0 - t) / self.falloff * gradient
+    }
+
+    /// Position within the falloff band (0 at the boundary, 1 at the outer edge)
+    fn falloff_position(&self, dist: f64) -> f64 {
+        if self.falloff > 0.0 {
+            dist / self.falloff
+        } else if dist > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+fn smoothstep(t: f64) -> f64 {
+    let t = t.clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Parse a circle given as "x,y,r"
+pub fn parse_circle(s: &str) -> Result<Region> {
+    let values = s
+        .split(',')
+        .map(|e| e.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure!(values.len() == 3, "Circle must be given as \"X,Y,R\"");
+    ensure!(values[2] > 0.0, "Radius of circle must be positive");
+
+    Ok(Region::Circle {
+        center: vector![values[0], values[1]],
+        radius: values[2],
+    })
+}
+
+/// Parse a polygon given as "x1,y1;x2,y2;x3,y3;..."
+pub fn parse_polygon(s: &str) -> Result<Vec<Vector2<f64>>> {
+    let points = s
+        .split(';')
+        .map(|point| {
+            let coords = point
+                .split(',')
+                .map(|e| e.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()?;
+            ensure!(coords.len() == 2, "Invalid polygon vertex: \"{}\"", point);
+            Ok(vector![coords[0], coords[1]])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(points.len() >= 3, "Polygon must have at least 3 vertices");
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polygon_weight() {
+        let mask = Mask {
+            region: Region::Polygon(parse_polygon("0,0; 10,0; 10,10; 0,10").unwrap()),
+            falloff: 2.0,
+        };
+
+        assert_eq!(mask.weight(5.0, 5.0), 1.0);
+        assert_eq!(mask.weight(11.0, 5.0), 0.5);
+        assert_eq!(mask.weight(12.0, 5.0), 0.0);
+        assert_eq!(mask.weight_gradient(11.0, 5.0), vector![-0.75, 0.0]);
+    }
+
+    #[test]
+    fn circle() {
+        assert!(matches!(
+            parse_circle("1, 2, 3").unwrap(),
+            Region::Circle { radius: 3.0, .. }
+        ));
+        assert!(parse_circle("1,2").is_err());
+        assert!(parse_circle("1,2,0").is_err());
+    }
+}
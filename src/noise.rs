@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Seeded fractal Perlin noise for textured layer surfaces.
+//!
+//!   offset = amplitude*(1 + Σ_k p^k*perlin(2^k*(x, y)/scale)/Σ_k p^k)  (p = 1/2, k < octaves)
+//! where perlin is scaled to about -1..=1.
+
+use na::{vector, Vector2};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+const PERSISTENCE: f64 = 0.5;
+const LACUNARITY: f64 = 2.0;
+/// Makes the range of 2D Perlin noise approximately -1..=1
+const NORMALIZATION: f64 = std::f64::consts::SQRT_2;
+
+/// Parameters of the noise. Only these are stored, and the permutation table is rebuilt from the seed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoiseParams {
+    /// Amplitude in mm (the offset varies in 0..=2*amplitude)
+    pub amplitude: f64,
+    /// Size of the largest features in mm
+    pub scale: f64,
+    pub octaves: u32,
+    pub seed: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "NoiseParams", into = "NoiseParams")]
+pub struct Noise {
+    params: NoiseParams,
+    /// Shuffled 0..256, repeated twice to avoid wrapping indices
+    permutation: Vec<usize>,
+}
+
+impl Noise {
+    pub fn new(params: NoiseParams) -> Self {
+        let mut permutation: Vec<usize> = (0..256).collect();
+
+        // Fisher-Yates shuffle with SplitMix64
+        let mut state = params.seed;
+        for i in (1..permutation.len()).rev() {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            permutation.swap(i, (z % (i as u64 + 1)) as usize);
+        }
+        permutation.extend_from_within(..);
+
+        Self {
+            params,
+            permutation,
+        }
+    }
+
+    /// Offset at (x, y) and its gradient
+    pub fn sample(&self, x: f64, y: f64) -> (f64, Vector2<f64>) {
+        let NoiseParams {
+            amplitude,
+            scale,
+            octaves,
+            ..
+        } = self.params;
+
+        let mut value = 0.0;
+        let mut gradient = Vector2::zeros();
+        let mut total_weight = 0.0;
+        let (mut weight, mut frequency) = (1.0, 1.0 / scale);
+        for _ in 0..octaves {
+            let (v, g) = self.perlin(x * frequency, y * frequency);
+            value += weight * v;
+            gradient += weight * frequency * g;
+            total_weight += weight;
+            weight *= PERSISTENCE;
+            frequency *= LACUNARITY;
+        }
+
+        if total_weight == 0.0 {
+            return (0.0, Vector2::zeros());
+        }
+        let factor = amplitude * NORMALIZATION / total_weight;
+        (amplitude + factor * value, factor * gradient)
+    }
+
+    /// 2D Perlin noise and its gradient
+    fn perlin(&self, x: f64, y: f64) -> (f64, Vector2<f64>) {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (i, j) = ((x0 as i64 & 255) as usize, (y0 as i64 & 255) as usize);
+
+        let g00 = self.gradient_at(i, j);
+        let g10 = self.gradient_at(i + 1, j);
+        let g01 = self.gradient_at(i, j + 1);
+        let g11 = self.gradient_at(i + 1, j + 1);
+
+        let n00 = g00.dot(&vector![fx, fy]);
+        let n10 = g10.dot(&vector![fx - 1.0, fy]);
+        let n01 = g01.dot(&vector![fx, fy - 1.0]);
+        let n11 = g11.dot(&vector![fx - 1.0, fy - 1.0]);
+
+        let (u, v) = (fade(fx), fade(fy));
+        let (du, dv) = (fade_deriv(fx), fade_deriv(fy));
+        let cross = n00 - n10 - n01 + n11;
+
+        let value = n00 + u * (n10 - n00) + v * (n01 - n00) + u * v * cross;
+        let gradient = g00
+            + u * (g10 - g00)
+            + v * (g01 - g00)
+            + u * v * (g00 - g10 - g01 + g11)
+            + vector![du * (n10 - n00 + v * cross), dv * (n01 - n00 + u * cross)];
+
+        (value, gradient)
+    }
+
+    /// Pseudorandom unit gradient at a lattice point
+    fn gradient_at(&self, i: usize, j: usize) -> Vector2<f64> {
+        let hash = self.permutation[self.permutation[i] + j];
+        let angle = (hash % 8) as f64 * std::f64::consts::FRAC_PI_4;
+        vector![angle.cos(), angle.sin()]
+    }
+}
+
+impl From<NoiseParams> for Noise {
+    fn from(value: NoiseParams) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<Noise> for NoiseParams {
+    fn from(value: Noise) -> Self {
+        value.params
+    }
+}
+
+/// 6t^5 - 15t^4 + 10t^3
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn fade_deriv(t: f64) -> f64 {
+    30.0 * t * t * (t - 1.0) * (t - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        let params = NoiseParams {
+            amplitude: 0.5,
+            scale: 10.0,
+            octaves: 3,
+            seed: 42,
+        };
+        let noise = Noise::new(params);
+
+        // Rebuilt from the stored parameters
+        let json = serde_json::to_string(&noise).unwrap();
+        let restored: Noise = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.sample(3.7, -1.2), noise.sample(3.7, -1.2));
+
+        // Different seed gives different texture
+        let other = Noise::new(NoiseParams { seed: 43, ..params });
+        assert_ne!(other.sample(3.7, -1.2).0, noise.sample(3.7, -1.2).0);
+
+        for (x, y) in [(3.7, -1.2), (-25.1, 40.3), (0.0, 0.0)] {
+            let (value, gradient) = noise.sample(x, y);
+            assert!((0.0..=1.0).contains(&value));
+
+            let h = 1e-6;
+            let numerical = vector![
+                (noise.sample(x + h, y).0 - noise.sample(x - h, y).0) / (2.0 * h),
+                (noise.sample(x, y + h).0 - noise.sample(x, y - h).0) / (2.0 * h)
+            ];
+            assert!((gradient - numerical).norm() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! 1D offset profile f(r) for surfaces of revolution.
+//!
+//! Supported file formats:
+//! - CSV with one "r, offset" pair per line
+//! - JSON array of [r, offset] pairs
+
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Error, Result};
+use clap::ValueEnum;
+use na::{vector, Vector2};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, ValueEnum)]
+pub enum ProfileInterpolation {
+    /// Piecewise linear segments
+    Linear,
+    /// Cubic Hermite spline with Catmull-Rom tangents (smooth slope)
+    #[default]
+    Spline,
+}
+
+/// Offset sampled at increasing distances from the axis.
+/// Inside the first point, the first value is kept.
+/// Outside the last point, the profile is extended with the slope at the end.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ProfileData")]
+pub struct Profile {
+    /// Pairs of (r, offset) in mm
+    pub points: Vec<Vector2<f64>>,
+    #[serde(default)]
+    pub interpolation: ProfileInterpolation,
+}
+
+/// Unchecked profile as written in JSON
+#[derive(Deserialize)]
+struct ProfileData {
+    points: Vec<Vector2<f64>>,
+    #[serde(default)]
+    interpolation: ProfileInterpolation,
+}
+
+impl TryFrom<ProfileData> for Profile {
+    type Error = Error;
+
+    fn try_from(data: ProfileData) -> Result<Self> {
+        Profile::new(data.points, data.interpolation)
+    }
+}
+
+impl Profile {
+    pub fn new(points: Vec<Vector2<f64>>, interpolation: ProfileInterpolation) -> Result<Self> {
+        ensure!(points.len() >= 2, "Profile must have at least 2 points");
+        ensure!(
+            points.iter().all(|p| p.x >= 0.0),
+            "Radii of profile must not be negative"
+        );
+        ensure!(
+            points.windows(2).all(|w| w[0].x < w[1].x),
+            "Radii of profile must be strictly increasing"
+        );
+
+        Ok(Self {
+            points,
+            interpolation,
+        })
+    }
+
+    pub fn load(path: &Path, interpolation: ProfileInterpolation) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        let text = std::fs::read_to_string(path)?;
+        let points = match extension.as_deref() {
+            Some("csv") => read_csv(&text)?,
+            Some("json") => serde_json::from_str(&text)?,
+            _ => bail!("Unsupported profile format: {}", path.display()),
+        };
+
+        Self::new(points, interpolation)
+            .with_context(|| format!("Invalid profile: {}", path.display()))
+    }
+
+    /// Offset at distance r from the axis and its derivative
+    pub fn sample(&self, r: f64) -> (f64, f64) {
+        let points = &self.points;
+        let (first, last) = (points[0], points[points.len() - 1]);
+
+        if r <= first.x {
+            return (first.y, 0.0);
+        } else if r >= last.x {
+            let slope = self.tangent(points.len() - 1);
+            return (last.y + slope * (r - last.x), slope);
+        }
+
+        // Segment containing r
+        let i = points.partition_point(|p| p.x <= r) - 1;
+        let (p0, p1) = (points[i], points[i + 1]);
+        let h = p1.x - p0.x;
+        let t = (r - p0.x) / h;
+
+        match self.interpolation {
+            ProfileInterpolation::Linear => {
+                let slope = (p1.y - p0.y) / h;
+                (p0.y + slope * (r - p0.x), slope)
+            }
+            ProfileInterpolation::Spline => {
+                let (m0, m1) = (self.tangent(i) * h, self.tangent(i + 1) * h);
+                let (t2, t3) = (t * t, t * t * t);
+                let value = (2.0 * t3 - 3.0 * t2 + 1.0) * p0.y
+                    + (t3 - 2.0 * t2 + t) * m0
+                    + (-2.0 * t3 + 3.0 * t2) * p1.y
+                    + (t3 - t2) * m1;
+                let deriv = (6.0 * t2 - 6.0 * t) * p0.y
+                    + (3.0 * t2 - 4.0 * t + 1.0) * m0
+                    + (-6.0 * t2 + 6.0 * t) * p1.y
+                    + (3.0 * t2 - 2.0 * t) * m1;
+                (value, deriv / h)
+            }
+        }
+    }
+
+    /// Slope at the i-th point
+    fn tangent(&self, i: usize) -> f64 {
+        let points = &self.points;
+        let (a, b) = match self.interpolation {
+            // Slope of the adjacent segment (the last one at the end)
+            ProfileInterpolation::Linear => (i.min(points.len() - 2), i.min(points.len() - 2) + 1),
+            ProfileInterpolation::Spline => (i.saturating_sub(1), (i + 1).min(points.len() - 1)),
+        };
+        (points[b].y - points[a].y) / (points[b].x - points[a].x)
+    }
+}
+
+/// Read "r, offset" pairs
+fn read_csv(text: &str) -> Result<Vec<Vector2<f64>>> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let values = line
+                .split(',')
+                .map(|e| e.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()?;
+            ensure!(values.len() == 2, "Invalid profile point: \"{}\"", line);
+            Ok(vector![values[0], values[1]])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        let points = read_csv("0, 0\n5, 1\n10, 4\n").unwrap();
+
+        let linear = Profile::new(points.clone(), ProfileInterpolation::Linear).unwrap();
+        assert_eq!(linear.sample(2.5), (0.5, 0.2));
+        assert_eq!(linear.sample(7.5), (2.5, 0.6));
+        // Extended with the last slope
+        assert_eq!(linear.sample(20.0), (10.0, 0.6));
+
+        let spline = Profile::new(points, ProfileInterpolation::Spline).unwrap();
+        assert_eq!(spline.sample(5.0).0, 1.0);
+        // Slope is continuous at the points
+        let h = 1e-9;
+        for r in [5.0, 10.0] {
+            assert!((spline.sample(r - h).1 - spline.sample(r + h).1).abs() < 1e-6);
+        }
+        for r in [1.0, 6.0, 9.0] {
+            let h = 1e-6;
+            let numerical = (spline.sample(r + h).0 - spline.sample(r - h).0) / (2.0 * h);
+            assert!((spline.sample(r).1 - numerical).abs() < 1e-6);
+        }
+
+        assert!(Profile::new(
+            vec![vector![1.0, 0.0], vector![1.0, 1.0]],
+            ProfileInterpolation::Linear
+        )
+        .is_err());
+
+        // Checked when loaded from JSON
+        assert!(serde_json::from_str::<Profile>(r#"{"points": []}"#).is_err());
+        assert!(serde_json::from_str::<Profile>(r#"{"points": [[5, 0], [1, 1]]}"#).is_err());
+        assert!(serde_json::from_str::<Profile>(r#"{"points": [[0, 0], [5, 1]]}"#).is_ok());
+    }
+}
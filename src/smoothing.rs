@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Post-processing of sampled offset fields, so that painted or generated fields become printable.
+//!
+//! 1. Gaussian blur with standard deviation `sigma`
+//! 2. Peaks steeper than `max_slope` are cut down (pyramids of the slope are fitted under them)
+//! 3. Gaussian blur of one sample is repeated until the curvature is below `max_curvature`.
+//!    Blurring never makes the slope steeper.
+//!
+//! Slope and curvature are measured by finite differences between adjacent samples.
+//! The differences along each axis are limited to tan(max_slope)/√2,
+//! so that the bilinearly interpolated surface is nowhere steeper than `max_slope`.
+
+use std::f64::consts::SQRT_2;
+
+use anyhow::{ensure, Result};
+
+use crate::heightmap::HeightMap;
+
+const MAX_CURVATURE_ITERATIONS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Smoothing {
+    /// Standard deviation of Gaussian blur in mm
+    pub sigma: f64,
+    /// Maximum slope angle in radians
+    pub max_slope: Option<f64>,
+    /// Maximum curvature (second derivative) in 1/mm
+    pub max_curvature: Option<f64>,
+}
+
+impl Smoothing {
+    pub fn apply(&self, map: &mut HeightMap) -> Result<()> {
+        let spacing = spacing(map);
+
+        if self.sigma > 0.0 {
+            blur(map, self.sigma / spacing.0, self.sigma / spacing.1);
+        }
+
+        if let Some(max_slope) = self.max_slope {
+            limit_slope(map, max_slope.tan() / SQRT_2);
+        }
+
+        if let Some(max_curvature) = self.max_curvature {
+            let mut iterations = 0;
+            while curvature(map) > max_curvature {
+                ensure!(
+                    iterations < MAX_CURVATURE_ITERATIONS,
+                    "Could not reduce curvature of offset field below {} /mm",
+                    max_curvature
+                );
+                blur(map, 1.0, 1.0);
+                iterations += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Distances between adjacent samples along X and Y
+fn spacing(map: &HeightMap) -> (f64, f64) {
+    (
+        map.size.x / (map.columns - 1) as f64,
+        map.size.y / (map.rows - 1) as f64,
+    )
+}
+
+/// Separable Gaussian blur. Standard deviations are in samples, and the edges are extended.
+fn blur(map: &mut HeightMap, sigma_x: f64, sigma_y: f64) {
+    let (columns, rows) = (map.columns, map.rows);
+
+    let kernel_x = gaussian_kernel(sigma_x);
+    let mut blurred = vec![0.0; map.values.len()];
+    for j in 0..rows {
+        for i in 0..columns {
+            blurred[j * columns + i] = convolve(&kernel_x, |k| {
+                map.values[j * columns + (i as isize + k).clamp(0, columns as isize - 1) as usize]
+            });
+        }
+    }
+
+    let kernel_y = gaussian_kernel(sigma_y);
+    for j in 0..rows {
+        for i in 0..columns {
+            map.values[j * columns + i] = convolve(&kernel_y, |k| {
+                blurred[(j as isize + k).clamp(0, rows as isize - 1) as usize * columns + i]
+            });
+        }
+    }
+}
+
+/// Normalized weights for offsets -radius..=radius (radius = 3 sigma)
+fn gaussian_kernel(sigma: f64) -> Vec<f64> {
+    let radius = (3.0 * sigma).ceil() as isize;
+    let weights: Vec<f64> = (-radius..=radius)
+        .map(|k| (-((k * k) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / sum).collect()
+}
+
+fn convolve(kernel: &[f64], value: impl Fn(isize) -> f64) -> f64 {
+    let radius = (kernel.len() / 2) as isize;
+    kernel
+        .iter()
+        .zip(-radius..=radius)
+        .map(|(w, k)| w * value(k))
+        .sum()
+}
+
+/// Lower samples so that no sample is higher than its 4 neighbors by more than slope*distance.
+/// Two raster sweeps in opposite directions propagate the limit across the whole grid.
+fn limit_slope(map: &mut HeightMap, slope: f64) {
+    let (columns, rows) = (map.columns as isize, map.rows as isize);
+    let (dx, dy) = spacing(map);
+
+    // Neighbors already visited in the forward sweep: (di, dj, distance)
+    let forward = [(0, -1, dy), (-1, 0, dx)];
+    let backward = forward.map(|(di, dj, d)| (-di, -dj, d));
+
+    let mut sweep = |neighbors: &[(isize, isize, f64)], order: &mut dyn Iterator<Item = isize>| {
+        for index in order {
+            let (i, j) = (index % columns, index / columns);
+            for &(di, dj, d) in neighbors {
+                let (ni, nj) = (i + di, j + dj);
+                if (0..columns).contains(&ni) && (0..rows).contains(&nj) {
+                    let limit = map.values[(nj * columns + ni) as usize] + slope * d;
+                    let value = &mut map.values[index as usize];
+                    *value = value.min(limit);
+                }
+            }
+        }
+    };
+    sweep(&forward, &mut (0..columns * rows));
+    sweep(&backward, &mut (0..columns * rows).rev());
+}
+
+/// Maximum of the absolute second differences along X and Y
+fn curvature(map: &HeightMap) -> f64 {
+    let (columns, rows) = (map.columns, map.rows);
+    let (dx, dy) = spacing(map);
+    let at = |i: usize, j: usize| map.values[j * columns + i];
+
+    let mut max: f64 = 0.0;
+    for j in 0..rows {
+        for i in 0..columns {
+            if 0 < i && i + 1 < columns {
+                let second = (at(i - 1, j) - 2.0 * at(i, j) + at(i + 1, j)) / (dx * dx);
+                max = max.max(second.abs());
+            }
+            if 0 < j && j + 1 < rows {
+                let second = (at(i, j - 1) - 2.0 * at(i, j) + at(i, j + 1)) / (dy * dy);
+                max = max.max(second.abs());
+            }
+        }
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heightmap::Interpolation;
+    use na::vector;
+    use nalgebra as na;
+
+    /// Maximum slope of the bilinearly interpolated surface (at the corners of the cells)
+    fn slope(map: &HeightMap) -> f64 {
+        let (columns, rows) = (map.columns, map.rows);
+        let (dx, dy) = spacing(map);
+        let at = |i: usize, j: usize| map.values[j * columns + i];
+        let mut max: f64 = 0.0;
+        for j in 0..rows - 1 {
+            for i in 0..columns - 1 {
+                for (ci, cj) in [(i, j), (i + 1, j + 1)] {
+                    let gradient = vector![
+                        (at(i + 1, cj) - at(i, cj)) / dx,
+                        (at(ci, j + 1) - at(ci, j)) / dy
+                    ];
+                    max = max.max(gradient.norm());
+                }
+            }
+        }
+        max
+    }
+
+    #[test]
+    fn apply() {
+        // Single spike on a flat field
+        let (columns, rows) = (21, 21);
+        let mut values = vec![0.0; columns * rows];
+        values[10 * columns + 10] = 5.0;
+        let map = HeightMap::new(
+            columns,
+            rows,
+            vector![10.0, 10.0],
+            values,
+            Interpolation::Bilinear,
+        )
+        .unwrap();
+
+        let mut blurred = map.clone();
+        Smoothing {
+            sigma: 1.0,
+            ..Default::default()
+        }
+        .apply(&mut blurred)
+        .unwrap();
+        // Volume is preserved away from the edges
+        let sum: f64 = blurred.values.iter().sum();
+        assert!((sum - 5.0).abs() < 1e-6);
+        assert!(blurred.values[10 * columns + 10] < 1.0);
+
+        let max_slope = 30f64.to_radians();
+        let max_curvature = 0.5;
+        let mut limited = map.clone();
+        Smoothing {
+            sigma: 0.0,
+            max_slope: Some(max_slope),
+            max_curvature: Some(max_curvature),
+        }
+        .apply(&mut limited)
+        .unwrap();
+        assert!(slope(&limited) <= max_slope.tan() + 1e-12);
+        assert!(curvature(&limited) <= max_curvature);
+    }
+}
@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Smooth offset surfaces defined by control points.
+//!
+//! Control points are laid out on a regular grid in the same way as `HeightMap`
+//! (centered on the transform center, the first row is at the largest Y).
+
+use anyhow::{ensure, Error, Result};
+use na::{vector, Vector2};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SurfaceKind {
+    /// Single Bezier patch whose degree is the number of control points minus 1 in each direction
+    Bezier,
+    /// Uniform bicubic B-spline, passing through the control points on the edges
+    BSpline,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "SurfaceData")]
+pub struct Surface {
+    pub kind: SurfaceKind,
+    pub columns: usize,
+    pub rows: usize,
+    /// Physical size of the control grid in mm
+    pub size: Vector2<f64>,
+    /// Offsets of control points in mm (row-major)
+    pub control_points: Vec<f64>,
+}
+
+/// Unchecked surface as written in JSON
+#[derive(Deserialize)]
+struct SurfaceData {
+    kind: SurfaceKind,
+    columns: usize,
+    rows: usize,
+    size: Vector2<f64>,
+    control_points: Vec<f64>,
+}
+
+impl TryFrom<SurfaceData> for Surface {
+    type Error = Error;
+
+    fn try_from(data: SurfaceData) -> Result<Self> {
+        Surface::new(
+            data.kind,
+            data.columns,
+            data.rows,
+            data.size,
+            data.control_points,
+        )
+    }
+}
+
+impl Surface {
+    pub fn new(
+        kind: SurfaceKind,
+        columns: usize,
+        rows: usize,
+        size: Vector2<f64>,
+        control_points: Vec<f64>,
+    ) -> Result<Self> {
+        ensure!(
+            columns >= 2 && rows >= 2,
+            "Surface must have at least 2x2 control points"
+        );
+        ensure!(
+            control_points.len() == columns * rows,
+            "Surface has {} control points but {}x{} grid is expected",
+            control_points.len(),
+            columns,
+            rows
+        );
+        ensure!(
+            size.x > 0.0 && size.y > 0.0,
+            "Surface size must be positive"
+        );
+
+        Ok(Self {
+            kind,
+            columns,
+            rows,
+            size,
+            control_points,
+        })
+    }
+
+    /// Offset at (x, y) and its gradient. Outside the grid, the edge is extended.
+    pub fn sample(&self, x: f64, y: f64) -> (f64, Vector2<f64>) {
+        // Normalized grid coordinates in 0..=1
+        let (u, inside_u) = clamp_unit(x / self.size.x + 0.5);
+        let (v, inside_v) = clamp_unit(0.5 - y / self.size.y);
+
+        let (weights_u, weights_v) = match self.kind {
+            SurfaceKind::Bezier => (bernstein(u, self.columns), bernstein(v, self.rows)),
+            SurfaceKind::BSpline => (b_spline(u, self.columns), b_spline(v, self.rows)),
+        };
+
+        let mut value = 0.0;
+        let mut gradient = Vector2::zeros();
+        for &(j, wv, dwv) in weights_v.iter() {
+            for &(i, wu, dwu) in weights_u.iter() {
+                let p = self.at(i, j);
+                value += wu * wv * p;
+                gradient += p * vector![dwu * wv, wu * dwv];
+            }
+        }
+
+        // Derivatives respect to (u, v) to XY (rows go toward -Y). The edge is flat outside.
+        let du_dx = if inside_u { 1.0 / self.size.x } else { 0.0 };
+        let dv_dy = if inside_v { -1.0 / self.size.y } else { 0.0 };
+        let gradient = vector![gradient.x * du_dx, gradient.y * dv_dy];
+
+        (value, gradient)
+    }
+
+    /// Control point. Indices just outside the grid are reflected (p[-1] = 2p[0] - p[1]).
+    fn at(&self, i: isize, j: isize) -> f64 {
+        let (last_i, last_j) = (self.columns as isize - 1, self.rows as isize - 1);
+        if i < 0 {
+            2.0 * self.at(0, j) - self.at(1, j)
+        } else if i > last_i {
+            2.0 * self.at(last_i, j) - self.at(last_i - 1, j)
+        } else if j < 0 {
+            2.0 * self.at(i, 0) - self.at(i, 1)
+        } else if j > last_j {
+            2.0 * self.at(i, last_j) - self.at(i, last_j - 1)
+        } else {
+            self.control_points[j as usize * self.columns + i as usize]
+        }
+    }
+}
+
+/// Clamp to 0..=1, also returning whether it was inside
+fn clamp_unit(t: f64) -> (f64, bool) {
+    (t.clamp(0.0, 1.0), (0.0..=1.0).contains(&t))
+}
+
+/// Bernstein polynomials of degree n - 1 at t, as (index, value, derivative)
+fn bernstein(t: f64, n: usize) -> Vec<(isize, f64, f64)> {
+    let degree = n - 1;
+    let basis = |i: usize, degree: usize| -> f64 {
+        if i > degree {
+            return 0.0;
+        }
+        binomial(degree, i) * t.powi(i as i32) * (1.0 - t).powi((degree - i) as i32)
+    };
+
+    (0..n)
+        .map(|i| {
+            let lower = if i > 0 { basis(i - 1, degree - 1) } else { 0.0 };
+            let deriv = (degree as f64) * (lower - basis(i, degree - 1));
+            (i as isize, basis(i, degree), deriv)
+        })
+        .collect()
+}
+
+/// Uniform cubic B-spline basis at t for n control points, as (index, value, derivative)
+fn b_spline(t: f64, n: usize) -> Vec<(isize, f64, f64)> {
+    let spans = (n - 1) as f64;
+    let u = t * spans;
+    let span = (u.floor() as isize).min(n as isize - 2);
+    let s = u - span as f64;
+
+    let values = [
+        (1.0 - s).powi(3) / 6.0,
+        (3.0 * s * s * s - 6.0 * s * s + 4.0) / 6.0,
+        (-3.0 * s * s * s + 3.0 * s * s + 3.0 * s + 1.0) / 6.0,
+        s * s * s / 6.0,
+    ];
+    let derivs = [
+        -(1.0 - s).powi(2) / 2.0,
+        (3.0 * s * s - 4.0 * s) / 2.0,
+        (-3.0 * s * s + 2.0 * s + 1.0) / 2.0,
+        s * s / 2.0,
+    ];
+
+    (0..4)
+        .map(|k| (span - 1 + k as isize, values[k], derivs[k] * spans))
+        .collect()
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        let control_points = vec![
+            0.0, 1.0, 0.5, 0.0, //
+            0.2, 2.0, 1.5, 0.3, //
+            0.0, 1.0, 3.0, 1.0, //
+            0.5, 0.0, 0.5, 2.0,
+        ];
+
+        for kind in [SurfaceKind::Bezier, SurfaceKind::BSpline] {
+            let surface =
+                Surface::new(kind, 4, 4, vector![30.0, 20.0], control_points.clone()).unwrap();
+
+            // Passes through the corners (first row is +Y)
+            assert!((surface.sample(-15.0, 10.0).0 - 0.0).abs() < 1e-12);
+            assert!((surface.sample(15.0, -10.0).0 - 2.0).abs() < 1e-12);
+
+            for (x, y) in [(3.0, -2.0), (-11.0, 7.5), (0.0, 0.0)] {
+                let h = 1e-6;
+                let numerical = vector![
+                    (surface.sample(x + h, y).0 - surface.sample(x - h, y).0) / (2.0 * h),
+                    (surface.sample(x, y + h).0 - surface.sample(x, y - h).0) / (2.0 * h)
+                ];
+                assert!((surface.sample(x, y).1 - numerical).norm() < 1e-6);
+            }
+        }
+    }
+    #[test]
+    fn new() {
+        let size = vector![10.0, 10.0];
+        assert!(Surface::new(SurfaceKind::Bezier, 2, 2, size, vec![0.0; 4]).is_ok());
+        assert!(Surface::new(SurfaceKind::Bezier, 1, 3, size, vec![0.0; 3]).is_err());
+        assert!(Surface::new(SurfaceKind::BSpline, 3, 3, size, vec![0.0; 8]).is_err());
+        assert!(
+            Surface::new(SurfaceKind::BSpline, 2, 2, vector![0.0, 10.0], vec![0.0; 4]).is_err()
+        );
+
+        // Also checked when loaded from JSON
+        let json = r#"{"kind": "Bezier", "columns": 3, "rows": 3, "size": [40, 40], "control_points": [0, 1, 2]}"#;
+        assert!(serde_json::from_str::<Surface>(json).is_err());
+    }
+}
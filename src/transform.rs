@@ -2,18 +2,39 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use anyhow::{bail, ensure, Context, Result};
 use clap::ValueEnum;
-use na::{vector, Vector3};
+use na::{matrix, vector, Matrix3, Vector2, Vector3};
 use nalgebra as na;
 use serde::{Deserialize, Serialize};
-use std::f64::consts::PI;
 
-use crate::utils::Aabb;
+use crate::{
+    blend::Blend,
+    expr::Expr,
+    heightmap::{HeightMap, Interpolation},
+    mask::Mask,
+    noise::Noise,
+    profile::Profile,
+    smoothing::Smoothing,
+    surface::Surface,
+    utils::{solve_monotonic_unbounded, solve_newton, Aabb},
+    wave::Wave,
+};
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransformData {
     pub transform: Transform,
+    /// AABB of the warped model, relative to the center
     pub warped_aabb: Aabb,
+    /// Center of the transform in the coordinates of the original model
+    #[serde(default)]
+    pub center: Option<Vector3<f64>>,
+    /// AABB of the original model
+    #[serde(default)]
+    pub original_aabb: Option<Aabb>,
+    /// Slope angle of conical transform chosen by `--auto-slope` (radians)
+    #[serde(default)]
+    pub auto_slope_angle: Option<f64>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
@@ -21,189 +42,958 @@ pub enum TransformType {
     Conical,
     Sinusoidal,
     Spherical,
+    HeightMap,
+    Expr,
+    Shear,
+    Tilt,
+    Twist,
+    RadialStretch,
+    Bend,
+    Revolved,
+    Noise,
+    Conformal,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Transform {
-    /// z' = z + tan(slope_angle)*sqrt(x^2 + y^2)
-    Conical { slope_angle: f64, flat_bottom: f64 },
-    /// z' = z + height*(sin(2*π*x/pitch)*cos(2*π*y/pitch)+1)/2
+    /// z' = z + tan(slope_angle)*sqrt(x^2 + y^2).
+    /// Within `apex_radius` from the axis, the apex is replaced by a spherical cap with the same slope at the boundary.
+    /// Elliptical when scaled by `anisotropy`.
+    Conical {
+        slope_angle: f64,
+        #[serde(default)]
+        apex_radius: f64,
+        #[serde(flatten)]
+        anisotropy: Anisotropy,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + height*(sin(2*π*x/pitch)*cos(2*π*y/pitch)+1)/2 by default.
+    /// See `Wave` for other patterns.
     Sinusoidal {
+        #[serde(flatten)]
+        wave: Wave,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + r - sign(r)*sqrt(r^2 - x^2 - y^2) (concave when r<0).
+    /// Ellipsoidal when scaled by `anisotropy`.
+    Spherical {
+        radius: f64,
+        #[serde(flatten)]
+        anisotropy: Anisotropy,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + profile(sqrt(x^2 + y^2)) (generalization of `Conical` and `Spherical`)
+    Revolved {
+        profile: Profile,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + surface(x, y)
+    Surface {
+        surface: Surface,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + noise(x, y) (random texture)
+    Noise {
+        noise: Noise,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + map(x, y)
+    HeightMap {
+        map: HeightMap,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + strength*z*(height/envelope(x, y) - 1), where `envelope` is the top surface of the model
+    /// and `height` is its maximum. Layers gradually take on the shape of the top surface,
+    /// and the top surface itself becomes a single layer when `strength` is 1.
+    Conformal {
+        envelope: HeightMap,
         height: f64,
-        pitch: f64,
-        flat_bottom: f64,
+        strength: f64,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + offset(x, y)
+    Expr {
+        offset: Expr,
+        #[serde(flatten)]
+        blend: Blend,
+    },
+    /// z' = z + tan(angle)*(x*cos(direction) + y*sin(direction)) (e.g. for belt printers)
+    Shear { angle: f64, direction: f64 },
+    /// z' = z + tan(angle)*(x*cos(direction) + y*sin(direction)), with flat bottom and flat top
+    /// (e.g. for parts which overhang in one direction)
+    Tilt {
+        angle: f64,
+        direction: f64,
+        #[serde(flatten)]
+        blend: Blend,
     },
-    /// z' = z + r - sqrt(r^2 - x^2 - y^2) (for r>0)
-    Spherical { radius: f64, flat_bottom: f64 },
+    /// Rotation about Z axis by rate*z (radians)
+    Twist { rate: f64 },
+    /// (x', y') = (1 + rate*z)*(x, y)
+    RadialStretch { rate: f64 },
+    /// Bending around an axis parallel to Y at z = -radius:
+    /// (x', z') = ((radius + z)*sin(x/radius), (radius + z)*cos(x/radius) - radius)
+    Bend { radius: f64 },
+    /// z' = z + weight(x, y)*offset(x, y) where weight is given by the mask.
+    /// The inner transform must not contain `Compose` or transforms which move X and Y.
+    Masked {
+        mask: Mask,
+        transform: Box<Transform>,
+    },
+    /// Inner transform applied around the given center
+    Centered {
+        center: Vector2<f64>,
+        transform: Box<Transform>,
+    },
+    /// Transforms applied in order
+    Compose(Vec<Transform>),
+}
+
+/// Scaling along rotated axes, which turns circular shapes into elliptical ones
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Anisotropy {
+    pub scale_x: f64,
+    pub scale_y: f64,
+    /// Rotation of the scaling axes, counterclockwise (radians)
+    pub rotation: f64,
+}
+
+impl Default for Anisotropy {
+    fn default() -> Self {
+        Self {
+            scale_x: 1.0,
+            scale_y: 1.0,
+            rotation: 0.0,
+        }
+    }
+}
+
+impl Anisotropy {
+    /// Coordinates in which the shape is circular
+    fn to_local(self, x: f64, y: f64) -> Vector2<f64> {
+        let (sin, cos) = self.rotation.sin_cos();
+        vector![
+            (cos * x + sin * y) / self.scale_x,
+            (-sin * x + cos * y) / self.scale_y
+        ]
+    }
+
+    /// Gradient in the local coordinates to the one in XY
+    fn gradient_to_global(self, gradient: Vector2<f64>) -> Vector2<f64> {
+        let (sin, cos) = self.rotation.sin_cos();
+        let (gu, gv) = (gradient.x / self.scale_x, gradient.y / self.scale_y);
+        vector![cos * gu - sin * gv, sin * gu + cos * gv]
+    }
 }
 
 impl Transform {
     pub fn apply(&self, point: Vector3<f64>) -> Vector3<f64> {
         match self {
-            &Transform::Conical {
+            Transform::Centered { center, transform } => {
+                let center = vector![center.x, center.y, 0.0];
+                transform.apply(point - center) + center
+            }
+            Transform::Compose(transforms) => transforms.iter().fold(point, |p, t| t.apply(p)),
+            _ if self.moves_xy() => self.apply_3d(point).0,
+            _ => vector![
+                point.x,
+                point.y,
+                self.blend().apply(point.z, self.offset(point))
+            ],
+        }
+    }
+
+    pub fn apply_inverse(&self, point: Vector3<f64>) -> Result<Vector3<f64>> {
+        match self {
+            Transform::Centered { center, transform } => {
+                let center = vector![center.x, center.y, 0.0];
+                Ok(transform.apply_inverse(point - center)? + center)
+            }
+            Transform::Compose(transforms) => transforms
+                .iter()
+                .rev()
+                .try_fold(point, |p, t| t.apply_inverse(p)),
+            _ if self.moves_xy() => {
+                // Newton's method seeded from the warped point itself
+                solve_newton(|p| self.apply_3d(p), point, point)
+            }
+            _ if self.depends_on_z() => {
+                // Offset changes with z, so solve z' = z + s(z)*offset(x, y, z) numerically
+                let z = solve_monotonic_unbounded(
+                    |z| {
+                        let p = vector![point.x, point.y, z];
+                        (self.apply(p).z, self.jacobian(p))
+                    },
+                    point.z,
+                    self.offset(point).abs().max(1.0),
+                )
+                .context("Layers are folded over: the warped Z must increase with Z")?;
+                Ok(vector![point.x, point.y, z])
+            }
+            _ => Ok(vector![
+                point.x,
+                point.y,
+                self.blend().apply_inverse(point.z, self.offset(point))?
+            ]),
+        }
+    }
+
+    /// Jacobian determinant of forward transform i.e. Ratio of volume magnification
+    pub fn jacobian(&self, point: Vector3<f64>) -> f64 {
+        match self {
+            Transform::Centered { center, transform } => {
+                transform.jacobian(point - vector![center.x, center.y, 0.0])
+            }
+            Transform::Compose(transforms) => {
+                // Chain rule: each Jacobian is evaluated at the point transformed by the preceding ones
+                let (_, jacobian) = transforms
+                    .iter()
+                    .fold((point, 1.0), |(p, j), t| (t.apply(p), j * t.jacobian(p)));
+                jacobian
+            }
+            _ if self.moves_xy() => self.apply_3d(point).1.determinant(),
+            _ => {
+                // ∂z'/∂z including the change of the offset itself along z
+                let blend = self.blend();
+                let offset = self.offset(point);
+                blend.jacobian(point.z, offset)
+                    + blend.offset_deriv(point.z, offset) * self.offset_z_deriv(point)
+            }
+        }
+    }
+
+    /// Slope of the layer surface z' = const in XY plane, i.e. ∇z'/(∂z'/∂z), pointing where it descends.
+    /// Unlike `offset_gradient`, it includes blending, z-dependent offsets and compositions.
+    pub fn layer_gradient(&self, point: Vector3<f64>) -> Vector2<f64> {
+        // Last row of the numerical Jacobian matrix
+        let h = NUMERICAL_DIFF_STEP;
+        let gradient = Vector3::from_fn(|i, _| {
+            let d = Vector3::ith(i, h);
+            (self.apply(point + d).z - self.apply(point - d).z) / (2.0 * h)
+        });
+        gradient.xy() / gradient.z
+    }
+
+    /// Gradient of offset (before flat bottom is applied) in XY plane
+    pub fn offset_gradient(&self, point: Vector3<f64>) -> Vector2<f64> {
+        let (x, y, z) = (point.x, point.y, point.z);
+        match *self {
+            Transform::Conical {
                 slope_angle,
-                flat_bottom,
+                apex_radius,
+                anisotropy,
+                ..
             } => {
-                vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom(
-                        point.z,
-                        conical_offset(point.x, point.y, slope_angle),
-                        flat_bottom
-                    )
-                ]
+                let p = anisotropy.to_local(x, y);
+                anisotropy.gradient_to_global(conical_gradient(p.x, p.y, slope_angle, apex_radius))
             }
-            &Transform::Sinusoidal {
-                height,
-                pitch,
-                flat_bottom,
+            Transform::Sinusoidal { wave, .. } => wave.gradient(x, y, z),
+            Transform::Spherical {
+                radius, anisotropy, ..
             } => {
-                vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom(
-                        point.z,
-                        sinusoidal_offset(point.x, point.y, height, pitch),
-                        flat_bottom
-                    )
-                ]
+                let p = anisotropy.to_local(x, y);
+                anisotropy.gradient_to_global(spherical_gradient(p.x, p.y, radius))
+            }
+            Transform::Surface { ref surface, .. } => surface.sample(x, y).1,
+            Transform::Noise { ref noise, .. } => noise.sample(x, y).1,
+            Transform::Revolved { ref profile, .. } => {
+                let r = (x * x + y * y).sqrt();
+                if r == 0.0 {
+                    Vector2::zeros()
+                } else {
+                    profile.sample(r).1 * vector![x, y] / r
+                }
             }
-            &Transform::Spherical {
-                radius,
-                flat_bottom,
+            Transform::Shear { angle, direction }
+            | Transform::Tilt {
+                angle, direction, ..
+            } => planar_gradient(angle, direction),
+            _ if self.moves_xy() => {
+                // Slope of the layer surface z' = const, i.e. ∇z'/(∂z'/∂z),
+                // pointing where it descends as the gradient of offset does
+                let (_, jacobian) = self.apply_3d(point);
+                vector![jacobian[(2, 0)], jacobian[(2, 1)]] / jacobian[(2, 2)]
+            }
+            Transform::Masked {
+                ref mask,
+                ref transform,
             } => {
+                if mask.weight(x, y) == 0.0 {
+                    return Vector2::zeros();
+                }
+                mask.weight(x, y) * transform.offset_gradient(point)
+                    + transform.offset(point) * mask.weight_gradient(x, y)
+            }
+            Transform::Centered {
+                center,
+                ref transform,
+            } => transform.offset_gradient(point - vector![center.x, center.y, 0.0]),
+            // Each member moves the point seen by the following ones
+            Transform::Compose(_) => self.layer_gradient(point),
+            _ => {
+                // Central difference
+                let h = NUMERICAL_DIFF_STEP;
                 vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom(
-                        point.z,
-                        spherical_offset(point.x, point.y, radius),
-                        flat_bottom
-                    )
+                    (self.offset(point + vector![h, 0.0, 0.0])
+                        - self.offset(point - vector![h, 0.0, 0.0]))
+                        / (2.0 * h),
+                    (self.offset(point + vector![0.0, h, 0.0])
+                        - self.offset(point - vector![0.0, h, 0.0]))
+                        / (2.0 * h)
                 ]
             }
         }
     }
 
-    pub fn apply_inverse(&self, point: Vector3<f64>) -> Vector3<f64> {
+    /// Transform whose offset field is sampled every `spacing` mm within ±`extent` mm
+    /// and post-processed by `smoothing`
+    pub fn smoothed(
+        &self,
+        smoothing: &Smoothing,
+        extent: Vector2<f64>,
+        spacing: f64,
+    ) -> Result<Transform> {
+        if !self.has_offset() || self.depends_on_z() {
+            bail!("Only a single offset field which does not depend on z can be smoothed");
+        }
+
+        let mut map = HeightMap::sampled(extent, spacing, Interpolation::Bilinear, |x, y| {
+            self.offset(vector![x, y, 0.0])
+        })?;
+
+        smoothing.apply(&mut map)?;
+        Ok(Transform::HeightMap {
+            map,
+            blend: self.blend(),
+        })
+    }
+
+    /// Whether the offset changes with z
+    pub fn depends_on_z(&self) -> bool {
         match self {
-            &Transform::Conical {
+            Transform::Sinusoidal { wave, .. } => wave.phase_shift != 0.0,
+            Transform::Expr { offset, .. } => offset.depends_on_z(),
+            Transform::Conformal { strength, .. } => *strength != 0.0,
+            Transform::Masked { transform, .. } | Transform::Centered { transform, .. } => {
+                transform.depends_on_z()
+            }
+            Transform::Compose(transforms) => transforms.iter().any(Transform::depends_on_z),
+            _ => false,
+        }
+    }
+
+    /// Z offset of the layer surface at the point before flat bottom is applied
+    fn offset(&self, point: Vector3<f64>) -> f64 {
+        let (x, y, z) = (point.x, point.y, point.z);
+        match *self {
+            Transform::Conical {
                 slope_angle,
-                flat_bottom,
+                apex_radius,
+                anisotropy,
+                ..
             } => {
-                vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom_inverse(
-                        point.z,
-                        conical_offset(point.x, point.y, slope_angle),
-                        flat_bottom
-                    )
-                ]
+                let p = anisotropy.to_local(x, y);
+                conical_offset(p.x, p.y, slope_angle, apex_radius)
             }
-            &Transform::Sinusoidal {
-                height,
-                pitch,
-                flat_bottom,
+            Transform::Sinusoidal { wave, .. } => wave.offset(x, y, z),
+            Transform::Spherical {
+                radius, anisotropy, ..
             } => {
-                vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom_inverse(
-                        point.z,
-                        sinusoidal_offset(point.x, point.y, height, pitch),
-                        flat_bottom
-                    )
-                ]
+                let p = anisotropy.to_local(x, y);
+                spherical_offset(p.x, p.y, radius)
             }
-            &Transform::Spherical {
-                radius,
-                flat_bottom,
+            Transform::Revolved { ref profile, .. } => profile.sample((x * x + y * y).sqrt()).0,
+            Transform::Surface { ref surface, .. } => surface.sample(x, y).0,
+            Transform::Noise { ref noise, .. } => noise.sample(x, y).0,
+            Transform::HeightMap { ref map, .. } => map.sample(x, y),
+            Transform::Conformal {
+                ref envelope,
+                height,
+                strength,
+                ..
+            } => strength * z * (height / conformal_envelope(envelope, height, x, y) - 1.0),
+            Transform::Expr { ref offset, .. } => offset.eval(x, y, z),
+            Transform::Shear { angle, direction }
+            | Transform::Tilt {
+                angle, direction, ..
+            } => planar_offset(x, y, angle, direction),
+            Transform::Masked {
+                ref mask,
+                ref transform,
             } => {
-                vector![
-                    point.x,
-                    point.y,
-                    apply_flat_bottom_inverse(
-                        point.z,
-                        spherical_offset(point.x, point.y, radius),
-                        flat_bottom
-                    )
-                ]
+                // Outside the region, the offset need not be defined (e.g. beyond a sphere)
+                let weight = mask.weight(x, y);
+                if weight == 0.0 {
+                    0.0
+                } else {
+                    weight * transform.offset(point)
+                }
+            }
+            Transform::Centered {
+                center,
+                ref transform,
+            } => transform.offset(point - vector![center.x, center.y, 0.0]),
+            Transform::Twist { .. } | Transform::RadialStretch { .. } | Transform::Bend { .. } => {
+                unreachable!("Transform which moves X and Y has no offset")
             }
+            Transform::Compose(_) => unreachable!("Composed transform has no single offset"),
         }
     }
 
-    /// Jacobian determinant of forward transform i.e. Ratio of volume magnification
-    pub fn jacobian(&self, point: Vector3<f64>) -> f64 {
+    /// Check the constraints on nesting which cannot be expressed by the types (e.g. for transforms read from JSON)
+    pub fn validate(&self) -> Result<()> {
         match self {
-            &Transform::Conical {
-                slope_angle,
-                flat_bottom,
-            } => jacobian_flat_bottom(
-                point.z,
-                conical_offset(point.x, point.y, slope_angle),
-                flat_bottom,
-            ),
-            &Transform::Sinusoidal {
+            Transform::Masked { transform, .. } => {
+                ensure!(
+                    transform.has_offset(),
+                    "Masked transform must not contain Compose or transforms which move X and Y"
+                );
+                transform.validate()
+            }
+            Transform::Centered { transform, .. } => transform.validate(),
+            Transform::Compose(transforms) => transforms.iter().try_for_each(Transform::validate),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the transform is a single offset field (i.e. `offset` and `blend` are defined)
+    pub fn has_offset(&self) -> bool {
+        match self {
+            Transform::Masked { transform, .. } | Transform::Centered { transform, .. } => {
+                transform.has_offset()
+            }
+            Transform::Compose(_) => false,
+            _ => !self.moves_xy(),
+        }
+    }
+
+    /// Whether the transform moves X and Y (not just offsets Z)
+    pub fn moves_xy(&self) -> bool {
+        matches!(
+            self,
+            Transform::Twist { .. } | Transform::RadialStretch { .. } | Transform::Bend { .. }
+        )
+    }
+
+    /// Point moved by a transform which moves X and Y, and its Jacobian matrix
+    fn apply_3d(&self, point: Vector3<f64>) -> (Vector3<f64>, Matrix3<f64>) {
+        let (x, y, z) = (point.x, point.y, point.z);
+        match *self {
+            Transform::Twist { rate } => {
+                let (sin, cos) = (rate * z).sin_cos();
+                let (x1, y1) = (cos * x - sin * y, sin * x + cos * y);
+                (
+                    vector![x1, y1, z],
+                    matrix![
+                        cos, -sin, -rate * y1;
+                        sin, cos, rate * x1;
+                        0.0, 0.0, 1.0
+                    ],
+                )
+            }
+            Transform::RadialStretch { rate } => {
+                let scale = 1.0 + rate * z;
+                (
+                    vector![scale * x, scale * y, z],
+                    matrix![
+                        scale, 0.0, rate * x;
+                        0.0, scale, rate * y;
+                        0.0, 0.0, 1.0
+                    ],
+                )
+            }
+            Transform::Bend { radius } => {
+                let (sin, cos) = (x / radius).sin_cos();
+                let distance = radius + z;
+                (
+                    vector![distance * sin, y, distance * cos - radius],
+                    matrix![
+                        distance * cos / radius, 0.0, sin;
+                        0.0, 1.0, 0.0;
+                        -distance * sin / radius, 0.0, cos
+                    ],
+                )
+            }
+            _ => unreachable!("Transform does not move X and Y"),
+        }
+    }
+
+    /// Derivative of offset respect to z
+    fn offset_z_deriv(&self, point: Vector3<f64>) -> f64 {
+        match *self {
+            Transform::Sinusoidal { wave, .. } => wave.z_deriv(point.x, point.y, point.z),
+            Transform::Expr { ref offset, .. } if offset.depends_on_z() => {
+                // Central difference
+                let h = NUMERICAL_DIFF_STEP;
+                (offset.eval(point.x, point.y, point.z + h)
+                    - offset.eval(point.x, point.y, point.z - h))
+                    / (2.0 * h)
+            }
+            Transform::Conformal {
+                ref envelope,
                 height,
-                pitch,
-                flat_bottom,
-            } => jacobian_flat_bottom(
-                point.z,
-                sinusoidal_offset(point.x, point.y, height, pitch),
-                flat_bottom,
-            ),
-            &Transform::Spherical {
-                radius,
-                flat_bottom,
-            } => jacobian_flat_bottom(
-                point.z,
-                spherical_offset(point.x, point.y, radius),
-                flat_bottom,
-            ),
+                strength,
+                ..
+            } => strength * (height / conformal_envelope(envelope, height, point.x, point.y) - 1.0),
+            Transform::Masked {
+                ref mask,
+                ref transform,
+            } => {
+                let weight = mask.weight(point.x, point.y);
+                if weight == 0.0 {
+                    0.0
+                } else {
+                    weight * transform.offset_z_deriv(point)
+                }
+            }
+            Transform::Centered {
+                center,
+                ref transform,
+            } => transform.offset_z_deriv(point - vector![center.x, center.y, 0.0]),
+            Transform::Compose(_) => unreachable!("Composed transform has no single offset"),
+            _ => 0.0,
+        }
+    }
+
+    fn blend(&self) -> Blend {
+        match *self {
+            Transform::Masked { ref transform, .. } | Transform::Centered { ref transform, .. } => {
+                transform.blend()
+            }
+            Transform::Conical { blend, .. }
+            | Transform::Sinusoidal { blend, .. }
+            | Transform::Spherical { blend, .. }
+            | Transform::Revolved { blend, .. }
+            | Transform::Surface { blend, .. }
+            | Transform::Noise { blend, .. }
+            | Transform::HeightMap { blend, .. }
+            | Transform::Conformal { blend, .. }
+            | Transform::Expr { blend, .. }
+            | Transform::Tilt { blend, .. } => blend,
+            Transform::Twist { .. } | Transform::RadialStretch { .. } | Transform::Bend { .. } => {
+                unreachable!("Transform which moves X and Y has no blend")
+            }
+            // The bottom is not parallel to the layers in the first place
+            Transform::Shear { .. } => Blend::default(),
+            Transform::Compose(_) => unreachable!("Composed transform has no single blend"),
         }
     }
 }
 
-fn conical_offset(x: f64, y: f64, slope_angle: f64) -> f64 {
+const NUMERICAL_DIFF_STEP: f64 = 1e-3; // mm
+
+/// Below this ratio to the height, the envelope is raised so that thin parts are not stretched infinitely
+const MIN_ENVELOPE_RATIO: f64 = 0.1;
+
+fn conformal_envelope(envelope: &HeightMap, height: f64, x: f64, y: f64) -> f64 {
+    envelope.sample(x, y).max(MIN_ENVELOPE_RATIO * height)
+}
+
+fn conical_offset(x: f64, y: f64, slope_angle: f64, apex_radius: f64) -> f64 {
     let s = slope_angle.tan();
-    s * (x * x + y * y).sqrt()
+    let r = (x * x + y * y).sqrt();
+    if r < apex_radius && s != 0.0 {
+        // Spherical cap of radius apex_radius/sin(slope_angle), tangent to the cone at r = apex_radius
+        let a = apex_radius;
+        s * a + (a - (a * a * (1.0 + s * s) - s * s * r * r).sqrt()) / s
+    } else {
+        s * r
+    }
 }
 
-fn sinusoidal_offset(x: f64, y: f64, height: f64, pitch: f64) -> f64 {
-    height * ((2.0 * PI * x / pitch).sin() * (2.0 * PI * y / pitch).cos() + 1.0) / 2.0
+fn conical_gradient(x: f64, y: f64, slope_angle: f64, apex_radius: f64) -> Vector2<f64> {
+    let s = slope_angle.tan();
+    let r = (x * x + y * y).sqrt();
+    if r < apex_radius {
+        let a = apex_radius;
+        s * vector![x, y] / (a * a * (1.0 + s * s) - s * s * r * r).sqrt()
+    } else if r == 0.0 {
+        Vector2::zeros()
+    } else {
+        s * vector![x, y] / r
+    }
 }
 
 fn spherical_offset(x: f64, y: f64, radius: f64) -> f64 {
-    radius - (radius * radius - x * x - y * y).sqrt()
+    // Negative radius flips the sphere into a downward-facing dome
+    radius - radius.signum() * (radius * radius - x * x - y * y).sqrt()
 }
 
-fn apply_flat_bottom(z: f64, offset: f64, flat_bottom: f64) -> f64 {
-    let strength = if flat_bottom != 0.0 {
-        (z / flat_bottom).min(1.0)
-    } else {
-        1.0
-    };
+fn spherical_gradient(x: f64, y: f64, radius: f64) -> Vector2<f64> {
+    radius.signum() * vector![x, y] / (radius * radius - x * x - y * y).sqrt()
+}
 
-    z + strength * offset
+fn planar_offset(x: f64, y: f64, angle: f64, direction: f64) -> f64 {
+    planar_gradient(angle, direction).dot(&vector![x, y])
 }
 
-fn apply_flat_bottom_inverse(z: f64, offset: f64, flat_bottom: f64) -> f64 {
-    if flat_bottom != 0.0 && z <= flat_bottom + offset {
-        (flat_bottom / (flat_bottom + offset)) * z
-    } else {
-        z - offset
-    }
+fn planar_gradient(angle: f64, direction: f64) -> Vector2<f64> {
+    angle.tan() * vector![direction.cos(), direction.sin()]
 }
 
-fn jacobian_flat_bottom(z: f64, offset: f64, flat_bottom: f64) -> f64 {
-    // Derivative of `strength` respect to z
-    let strength_deriv = if flat_bottom != 0.0 && z <= flat_bottom {
-        1.0 / flat_bottom
-    } else {
-        0.0
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blend::BlendProfile, mask::Region, profile::ProfileInterpolation, wave::Waveform};
+    use std::f64::consts::PI;
+
+    fn wave(height: f64, pitch: f64) -> Wave {
+        Wave {
+            height,
+            pitch,
+            pitch_y: None,
+            phase: 0.0,
+            rotation: 0.0,
+            phase_shift: 0.0,
+            waveform: Waveform::Sine,
+            corrugation: false,
+        }
+    }
+
+    #[test]
+    fn concave_spherical() {
+        let transform = Transform::Spherical {
+            radius: -50.0,
+            anisotropy: Anisotropy::default(),
+            blend: Blend::new(0.0, BlendProfile::Linear),
+        };
+
+        // Dome: the center is the highest point
+        assert_eq!(transform.apply(vector![0.0, 0.0, 1.0]).z, 1.0);
+        assert!(transform.apply(vector![30.0, 0.0, 1.0]).z < 1.0);
+
+        let point = vector![12.0, -7.0, 3.0];
+        let warped = transform.apply(point);
+        assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+        assert_eq!(transform.jacobian(point), 1.0);
+    }
+
+    #[test]
+    fn negative_conical_flat_bottom() {
+        let transform = Transform::Conical {
+            slope_angle: -PI / 4.0,
+            apex_radius: 0.0,
+            anisotropy: Anisotropy::default(),
+            blend: Blend::new(2.0, BlendProfile::Linear),
+        };
+
+        // Bottom stays on the bed
+        assert_eq!(transform.apply(vector![20.0, 0.0, 0.0]).z, 0.0);
 
-    1.0 + strength_deriv * offset
+        for point in [
+            vector![20.0, 0.0, 1.0],
+            vector![20.0, 0.0, 30.0],
+            vector![0.5, 0.5, 1.0],
+        ] {
+            let warped = transform.apply(point);
+            assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+            assert!(transform.jacobian(point) > 0.0);
+        }
+    }
+
+    #[test]
+    fn apex_rounding() {
+        for slope_angle in [PI / 6.0, -PI / 4.0] {
+            let (s, a) = (f64::tan(slope_angle), 3.0);
+
+            // Continuous with the same slope at the boundary of the cap
+            let h = 1e-9;
+            assert!((conical_offset(a - h, 0.0, slope_angle, a) - s * a).abs() < 1e-6);
+            assert!((conical_gradient(a - h, 0.0, slope_angle, a).x - s).abs() < 1e-6);
+
+            // Flat at the center
+            assert_eq!(conical_gradient(0.0, 0.0, slope_angle, a), Vector2::zeros());
+            assert!(conical_offset(0.0, 0.0, slope_angle, a).abs() < s.abs() * a);
+        }
+    }
+
+    #[test]
+    fn compose() {
+        let conical = Transform::Conical {
+            slope_angle: PI / 6.0,
+            apex_radius: 0.0,
+            anisotropy: Anisotropy::default(),
+            blend: Blend::new(1.0, BlendProfile::Linear),
+        };
+        let sinusoidal = Transform::Sinusoidal {
+            wave: wave(1.0, 5.0),
+            blend: Blend::new(3.0, BlendProfile::Linear),
+        };
+        let transform = Transform::Compose(vec![conical.clone(), sinusoidal.clone()]);
+
+        let point = vector![3.0, 1.5, 0.5];
+        let warped = transform.apply(point);
+        assert_eq!(warped, sinusoidal.apply(conical.apply(point)));
+        assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+
+        // Compare with numerical derivative along z
+        let h = 1e-6;
+        let numerical = (transform.apply(point + vector![0.0, 0.0, h]).z
+            - transform.apply(point - vector![0.0, 0.0, h]).z)
+            / (2.0 * h);
+        assert!((transform.jacobian(point) - numerical).abs() < 1e-6);
+    }
+
+    #[test]
+    fn offset_gradient() {
+        let transforms = [
+            Transform::Conical {
+                slope_angle: -PI / 6.0,
+                apex_radius: 0.0,
+                anisotropy: Anisotropy::default(),
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Conical {
+                slope_angle: PI / 5.0,
+                apex_radius: 5.0,
+                anisotropy: Anisotropy::default(),
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Sinusoidal {
+                wave: wave(2.0, 7.0),
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Tilt {
+                angle: PI / 6.0,
+                direction: 2.0,
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Conical {
+                slope_angle: PI / 5.0,
+                apex_radius: 1.0,
+                anisotropy: Anisotropy {
+                    scale_x: 3.0,
+                    scale_y: 0.5,
+                    rotation: 0.4,
+                },
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Spherical {
+                radius: -40.0,
+                anisotropy: Anisotropy {
+                    scale_x: 0.7,
+                    scale_y: 2.0,
+                    rotation: -1.0,
+                },
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Revolved {
+                profile: Profile::new(
+                    vec![vector![0.0, 0.0], vector![2.0, 0.5], vector![6.0, 1.0]],
+                    ProfileInterpolation::Spline,
+                )
+                .unwrap(),
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+            Transform::Spherical {
+                radius: 40.0,
+                anisotropy: Anisotropy::default(),
+                blend: Blend::new(0.0, BlendProfile::Linear),
+            },
+        ];
+
+        for transform in transforms {
+            let point = vector![3.0, -2.0, 0.0];
+            let h = 1e-6;
+            let (dx, dy) = (vector![h, 0.0, 0.0], vector![0.0, h, 0.0]);
+            let numerical = vector![
+                (transform.offset(point + dx) - transform.offset(point - dx)) / (2.0 * h),
+                (transform.offset(point + dy) - transform.offset(point - dy)) / (2.0 * h)
+            ];
+            assert!((transform.offset_gradient(point) - numerical).norm() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn z_dependent() {
+        let transforms = [
+            Transform::Sinusoidal {
+                wave: Wave {
+                    phase_shift: 0.5,
+                    ..wave(1.0, 5.0)
+                },
+                blend: Blend::new(2.0, BlendProfile::Smoothstep).with_flat_top(3.0, 20.0),
+            },
+            Transform::Expr {
+                offset: Expr::parse("0.3*sin(x + z)").unwrap(),
+                blend: Blend::new(1.0, BlendProfile::Linear),
+            },
+        ];
+
+        for transform in transforms {
+            for point in [
+                vector![1.0, 2.0, 0.5],
+                vector![-3.0, 0.5, 7.0],
+                vector![2.0, -1.0, 19.0],
+            ] {
+                let warped = transform.apply(point);
+                assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+
+                // Compare with numerical derivative along z
+                let h = 1e-6;
+                let dz = vector![0.0, 0.0, h];
+                let numerical =
+                    (transform.apply(point + dz).z - transform.apply(point - dz).z) / (2.0 * h);
+                assert!((transform.jacobian(point) - numerical).abs() < 1e-4);
+            }
+        }
+
+        // z - 2z decreases with z, so no z is warped to the point
+        let folded = Transform::Expr {
+            offset: Expr::parse("-2*z").unwrap(),
+            blend: Blend::new(0.0, BlendProfile::Linear),
+        };
+        assert!(folded.apply_inverse(vector![1.0, 2.0, 3.0]).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let masked = |transform: Transform| Transform::Masked {
+            mask: Mask {
+                region: Region::Circle {
+                    center: Vector2::zeros(),
+                    radius: 10.0,
+                },
+                falloff: 1.0,
+            },
+            transform: Box::new(transform),
+        };
+        let tilt = Transform::Tilt {
+            angle: 0.1,
+            direction: 0.0,
+            blend: Blend::default(),
+        };
+
+        assert!(masked(tilt.clone()).validate().is_ok());
+        assert!(
+            Transform::Compose(vec![Transform::Twist { rate: 0.1 }, masked(tilt.clone())])
+                .validate()
+                .is_ok()
+        );
+        assert!(masked(Transform::Compose(vec![tilt.clone()]))
+            .validate()
+            .is_err());
+        assert!(masked(Transform::Centered {
+            center: Vector2::zeros(),
+            transform: Box::new(Transform::Bend { radius: 50.0 }),
+        })
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn masked_outside() {
+        // Sphere is undefined beyond its radius, but masked out there
+        let transform = Transform::Masked {
+            mask: Mask {
+                region: Region::Circle {
+                    center: Vector2::zeros(),
+                    radius: 15.0,
+                },
+                falloff: 5.0,
+            },
+            transform: Box::new(Transform::Spherical {
+                radius: -50.0,
+                anisotropy: Anisotropy::default(),
+                blend: Blend::new(1.0, BlendProfile::Linear),
+            }),
+        };
+        let point = vector![60.0, 0.0, 3.0];
+        assert_eq!(transform.apply(point), point);
+        assert_eq!(transform.jacobian(point), 1.0);
+        assert_eq!(transform.offset_gradient(point), Vector2::zeros());
+    }
+
+    #[test]
+    fn conformal() {
+        // Top surface sloping along X
+        let envelope =
+            HeightMap::sampled(vector![10.0, 5.0], 1.0, Interpolation::Bilinear, |x, _| {
+                5.0 + x / 4.0
+            })
+            .unwrap();
+        let transform = Transform::Conformal {
+            envelope,
+            height: 7.5,
+            strength: 1.0,
+            blend: Blend::new(0.0, BlendProfile::Linear),
+        };
+
+        // The top surface becomes a single layer, and the bottom stays
+        for x in [-8.0, 0.0, 6.5] {
+            assert!((transform.apply(vector![x, 1.0, 5.0 + x / 4.0]).z - 7.5).abs() < 1e-12);
+            assert_eq!(transform.apply(vector![x, 1.0, 0.0]).z, 0.0);
+        }
+
+        let point = vector![-3.0, 2.0, 2.0];
+        let warped = transform.apply(point);
+        assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+        assert!((transform.jacobian(point) - 7.5 / 4.25).abs() < 1e-12);
+
+        // The top layer rises along X as the top surface does
+        let gradient = transform.layer_gradient(vector![2.0, 1.0, 5.5]);
+        assert!((gradient - vector![-0.25, 0.0]).norm() < 1e-6);
+    }
+
+    #[test]
+    fn moving_xy() {
+        let transforms = [
+            Transform::Twist { rate: 0.05 },
+            Transform::RadialStretch { rate: 0.02 },
+            Transform::Bend { radius: 40.0 },
+            Transform::Compose(vec![
+                Transform::Conical {
+                    slope_angle: PI / 6.0,
+                    apex_radius: 0.0,
+                    anisotropy: Anisotropy::default(),
+                    blend: Blend::new(1.0, BlendProfile::Linear),
+                },
+                Transform::Twist { rate: -0.1 },
+            ]),
+            Transform::Compose(vec![
+                Transform::Twist { rate: 0.1 },
+                Transform::Tilt {
+                    angle: 0.5,
+                    direction: 0.0,
+                    blend: Blend::default(),
+                },
+            ]),
+        ];
+
+        for transform in transforms {
+            for point in [vector![3.0, -2.0, 0.5], vector![-10.0, 4.0, 15.0]] {
+                let warped = transform.apply(point);
+                assert!((transform.apply_inverse(warped).unwrap() - point).norm() < 1e-9);
+
+                // Compare with determinant of numerical Jacobian matrix
+                let h = 1e-6;
+                let numerical = Matrix3::from_fn(|i, j| {
+                    let d = Vector3::ith(j, h);
+                    (transform.apply(point + d)[i] - transform.apply(point - d)[i]) / (2.0 * h)
+                });
+                assert!((transform.jacobian(point) - numerical.determinant()).abs() < 1e-6);
+
+                // Moving along the slope stays on the same layer
+                let gradient = transform.offset_gradient(point);
+                let step = vector![h, h / 2.0];
+                let along = point + vector![step.x, step.y, -gradient.dot(&step)];
+                assert!((transform.apply(along).z - warped.z).abs() < 1e-9);
+            }
+        }
+
+        // Bent layers rise away from the center, so they descend toward it (-X at positive X)
+        let bend = Transform::Bend { radius: 40.0 };
+        let gradient = bend.offset_gradient(vector![10.0, 0.0, 5.0]);
+        assert!((gradient.x + 45.0 / 40.0 * 0.25f64.tan()).abs() < 1e-12);
+        assert_eq!(gradient.y, 0.0);
+    }
 }
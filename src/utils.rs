@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use na::vector;
 use nalgebra as na;
 use serde::{Deserialize, Serialize};
@@ -36,7 +36,7 @@ impl From<Mesh> for stl_io::IndexedMesh {
                 let tri = tri_idx.map(|i| value.vertices[i]);
                 stl_io::IndexedTriangle {
                     normal: from_na((tri[1] - tri[0]).cross(&(tri[2] - tri[1]))),
-                    vertices: tri_idx.clone(),
+                    vertices: *tri_idx,
                 }
             })
             .collect();
@@ -65,3 +65,123 @@ pub fn parse_vector(s: &str) -> Result<na::Vector3<f64>> {
 
     Ok(vector![x, y, z])
 }
+
+/// Solve f(x) = target for monotonically increasing f in [lo, hi].
+/// `f` returns the value and the derivative.
+pub fn solve_monotonic(f: impl Fn(f64) -> (f64, f64), target: f64, lo: f64, hi: f64) -> f64 {
+    const TOLERANCE: f64 = 1e-12;
+    const MAX_ITERATIONS: usize = 100;
+
+    let (mut lo, mut hi) = (lo, hi);
+    let mut x = (lo + hi) / 2.0;
+    for _ in 0..MAX_ITERATIONS {
+        let (value, deriv) = f(x);
+        let error = value - target;
+        if error.abs() < TOLERANCE {
+            break;
+        }
+
+        if error > 0.0 {
+            hi = x;
+        } else {
+            lo = x;
+        }
+
+        // Newton step, falling back to bisection when it leaves the bracket
+        let next = x - error / deriv;
+        x = if next > lo && next < hi {
+            next
+        } else {
+            (lo + hi) / 2.0
+        };
+    }
+
+    x
+}
+
+/// Solve f(x) = target for monotonically increasing f, without a known bracket.
+/// The search range is expanded from `target ± width` until it contains the solution.
+/// Fails when f is not monotonic, e.g. its range does not contain the target.
+pub fn solve_monotonic_unbounded(
+    f: impl Fn(f64) -> (f64, f64),
+    target: f64,
+    width: f64,
+) -> Result<f64> {
+    const MAX_EXPANSIONS: usize = 64;
+    const TOLERANCE: f64 = 1e-9;
+
+    let mut width = width;
+    let (mut lo, mut hi) = (target - width, target + width);
+    let mut expansions = 0;
+    while f(lo).0 > target || f(hi).0 < target {
+        ensure!(
+            expansions < MAX_EXPANSIONS,
+            "Could not find z which is warped to {}",
+            target
+        );
+        width *= 2.0;
+        (lo, hi) = (target - width, target + width);
+        expansions += 1;
+    }
+
+    let x = solve_monotonic(&f, target, lo, hi);
+    // NaN also fails
+    let error = (f(x).0 - target).abs();
+    ensure!(
+        error <= TOLERANCE * target.abs().max(1.0),
+        "Could not find z which is warped to {} (error {})",
+        target,
+        error
+    );
+    Ok(x)
+}
+
+/// Solve f(p) = target by Newton's method with backtracking line search, starting from `initial`.
+/// `f` returns the value and the Jacobian matrix.
+pub fn solve_newton(
+    f: impl Fn(na::Vector3<f64>) -> (na::Vector3<f64>, na::Matrix3<f64>),
+    target: na::Vector3<f64>,
+    initial: na::Vector3<f64>,
+) -> Result<na::Vector3<f64>> {
+    const TOLERANCE: f64 = 1e-10;
+    const MAX_ITERATIONS: usize = 100;
+    const MIN_STEP_SCALE: f64 = 1e-6;
+
+    let mut p = initial;
+    let (mut value, mut jacobian) = f(p);
+    for _ in 0..MAX_ITERATIONS {
+        let error = (value - target).norm();
+        if error < TOLERANCE {
+            return Ok(p);
+        }
+
+        let Some(step) = jacobian.lu().solve(&(value - target)) else {
+            bail!(
+                "Could not find the point which is warped to {:?} (singular Jacobian)",
+                target.as_slice()
+            );
+        };
+
+        // Shorten the step until the error decreases
+        let mut scale = 1.0;
+        loop {
+            let next = p - scale * step;
+            let (next_value, next_jacobian) = f(next);
+            if (next_value - target).norm() < error || scale < MIN_STEP_SCALE {
+                p = next;
+                (value, jacobian) = (next_value, next_jacobian);
+                break;
+            }
+            scale /= 2.0;
+        }
+    }
+
+    let error = (value - target).norm();
+    ensure!(
+        error < TOLERANCE,
+        "Could not find the point which is warped to {:?} (error {})",
+        target.as_slice(),
+        error
+    );
+    Ok(p)
+}
@@ -2,27 +2,78 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{ffi::OsString, fs::File, path::Path};
+use std::{f64::consts::PI, ffi::OsString, fs::File, path::Path};
 
-use anyhow::Result;
-use clap::Args;
-use na::{vector, Vector3};
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Args, ValueEnum};
+use na::{vector, Vector2, Vector3};
 use nalgebra as na;
 use stl_io::{IndexedMesh, Triangle};
 
 use crate::{
+    blend::{Blend, BlendProfile},
+    expr::Expr,
+    heightmap::{HeightMap, Interpolation},
+    mask::{parse_circle, parse_polygon, Mask, Region},
+    noise::{Noise, NoiseParams},
+    profile::{Profile, ProfileInterpolation},
+    smoothing::Smoothing,
     tessellation::tesselate,
-    transform::{Transform, TransformData, TransformType},
+    transform::{Anisotropy, Transform, TransformData, TransformType},
     utils::{parse_vector, Aabb, Mesh},
+    wave::{Wave, Waveform},
 };
 
 const DEFAULT_MAX_EDGE_LEN: f64 = 1.0; // 1 mm
 const DEFAULT_TYPE: TransformType = TransformType::Conical;
 const DEFAULT_SLOPE_ANGLE: f64 = 30.0; // degrees
+const DEFAULT_APEX_RADIUS: f64 = 0.0; // mm
+const DEFAULT_SCALE: f64 = 1.0;
+const DEFAULT_TILT_ANGLE: f64 = 30.0; // degrees
+const DEFAULT_TILT_DIRECTION: f64 = 0.0; // degrees (X axis)
 const DEFAULT_HEIGHT: f64 = 2.0; // mm
 const DEFAULT_PITCH: f64 = 10.0; // mm
+const DEFAULT_PHASE: f64 = 0.0; // degrees
+const DEFAULT_WAVE_ROTATION: f64 = 0.0; // degrees
+const DEFAULT_ANISOTROPY_ROTATION: f64 = 0.0; // degrees
+const DEFAULT_PHASE_SHIFT: f64 = 0.0; // degrees/mm
+const DEFAULT_WAVEFORM: Waveform = Waveform::Sine;
 const DEFAULT_RADIUS: f64 = 100.0; // mm
 const DEFAULT_FLAT_BOTTOM: f64 = 0.0; // mm
+const DEFAULT_FLAT_TOP: f64 = 0.0; // mm
+const DEFAULT_BLEND_PROFILE: BlendProfile = BlendProfile::Linear;
+const DEFAULT_MASK_FALLOFF: f64 = 5.0; // mm
+const DEFAULT_SHEAR_ANGLE: f64 = 45.0; // degrees
+const DEFAULT_SHEAR_DIRECTION: f64 = 90.0; // degrees (Y axis)
+const DEFAULT_TWIST_RATE: f64 = 1.0; // degrees/mm
+const DEFAULT_STRETCH_RATE: f64 = 0.01; // 1/mm
+const DEFAULT_NOISE_AMPLITUDE: f64 = 0.5; // mm
+const DEFAULT_NOISE_SCALE: f64 = 10.0; // mm
+const DEFAULT_NOISE_OCTAVES: u32 = 3;
+const DEFAULT_NOISE_SEED: u64 = 0;
+const DEFAULT_CONFORMAL_SMOOTH: f64 = 2.0; // mm
+const DEFAULT_CONFORMAL_STRENGTH: f64 = 1.0;
+const DEFAULT_PRINTABLE_ANGLE: f64 = 45.0; // degrees
+const AUTO_SLOPE_STEP: f64 = 0.5; // degrees
+const AUTO_SLOPE_MAX: f64 = 60.0; // degrees
+/// Overhangs steeper than the printable angle by less than this are still printable (rounding errors)
+const OVERHANG_TOLERANCE: f64 = 1e-3; // degrees
+/// Faces lower than this from the bottom of the model are on the bed
+const BED_TOLERANCE: f64 = 1e-3; // mm
+/// Step of numerical differentiation for the normals of layer surfaces
+const LAYER_NORMAL_STEP: f64 = 1e-3; // mm
+/// Limit of points sampled over the model when checking the transform
+const MAX_GRID_SAMPLES: usize = 100_000;
+const DEFAULT_CLEARANCE_ACTION: ClearanceAction = ClearanceAction::Warn;
+const DEFAULT_INTERPOLATION: Interpolation = Interpolation::Bilinear;
+const DEFAULT_PROFILE_INTERPOLATION: ProfileInterpolation = ProfileInterpolation::Spline;
+
+/// What to do when the layer surface is steeper than the nozzle clearance
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum ClearanceAction {
+    Warn,
+    Fail,
+}
 
 #[derive(Args)]
 pub struct WarpArgs {
@@ -31,63 +82,236 @@ pub struct WarpArgs {
     output_file: Option<OsString>,
     #[arg(short, long, default_value_t = DEFAULT_MAX_EDGE_LEN)]
     max_edge_len: f64,
-    #[arg(short = 't', long = "type", value_enum, default_value_t = DEFAULT_TYPE)]
-    transform_type: TransformType,
+    /// Transform type. Multiple types are applied in the given order
+    #[arg(short = 't', long = "type", value_enum, default_values_t = [DEFAULT_TYPE])]
+    transform_types: Vec<TransformType>,
     #[arg(short, long, default_value_t = DEFAULT_SLOPE_ANGLE)]
     slope_angle: f64,
+    /// Radius of the rounded apex of conical transform
+    #[arg(long, default_value_t = DEFAULT_APEX_RADIUS)]
+    apex_radius: f64,
+    /// Scale of conical or spherical transform along X axis (rotated by --anisotropy-rotation)
+    #[arg(long, default_value_t = DEFAULT_SCALE)]
+    scale_x: f64,
+    /// Scale of conical or spherical transform along Y axis (rotated by --anisotropy-rotation)
+    #[arg(long, default_value_t = DEFAULT_SCALE)]
+    scale_y: f64,
+    /// Rotation of the axes of --scale-x and --scale-y, counterclockwise in degrees
+    #[arg(long, default_value_t = DEFAULT_ANISOTROPY_ROTATION)]
+    anisotropy_rotation: f64,
+    #[arg(long, default_value_t = DEFAULT_TILT_ANGLE)]
+    tilt_angle: f64,
+    /// Direction in which the layers of tilt transform rise, counterclockwise from X axis in degrees
+    #[arg(long, default_value_t = DEFAULT_TILT_DIRECTION)]
+    tilt_direction: f64,
     #[arg(short = 'H', long, default_value_t = DEFAULT_HEIGHT)]
     height: f64,
+    /// Pitch of sinusoidal pattern (along X axis if --pitch-y is given)
     #[arg(short, long, default_value_t = DEFAULT_PITCH)]
     pitch: f64,
+    /// Pitch of sinusoidal pattern along Y axis
+    #[arg(long)]
+    pitch_y: Option<f64>,
+    /// Phase of sinusoidal pattern along X axis in degrees
+    #[arg(long, default_value_t = DEFAULT_PHASE)]
+    phase: f64,
+    /// Rotation of sinusoidal pattern, counterclockwise in degrees
+    #[arg(long, default_value_t = DEFAULT_WAVE_ROTATION)]
+    wave_rotation: f64,
+    /// Change of phase of sinusoidal pattern per mm of height in degrees (e.g. for interlocking layers)
+    #[arg(long, default_value_t = DEFAULT_PHASE_SHIFT)]
+    phase_shift: f64,
+    #[arg(long, value_enum, default_value_t = DEFAULT_WAVEFORM)]
+    waveform: Waveform,
+    /// Make sinusoidal pattern vary only along X axis (corrugation)
+    #[arg(long)]
+    corrugation: bool,
     #[arg(short, long, default_value_t = DEFAULT_RADIUS)]
     radius: f64,
     #[arg(long, default_value_t = DEFAULT_FLAT_BOTTOM)]
     flat_bottom: f64,
+    /// Height below the top of the model over which the offset fades out
+    #[arg(long, default_value_t = DEFAULT_FLAT_TOP)]
+    flat_top: f64,
+    /// Profile of the transition from flat bottom to the full offset
+    #[arg(long, value_enum, default_value_t = DEFAULT_BLEND_PROFILE)]
+    blend_profile: BlendProfile,
+    /// Center of the transform. If given multiple times, the transform is applied around each of them
     #[arg(short, long, value_parser = parse_vector)]
-    center: Option<Vector3<f64>>,
+    center: Vec<Vector3<f64>>,
+    /// Radius of the region of influence of each center
+    /// (defaults to half the distance between centers minus the mask falloff)
+    #[arg(long)]
+    influence_radius: Option<f64>,
+    /// Read the transform from a JSON file (same format as "transform" in *.transform.json)
+    #[arg(long)]
+    transform_json: Option<OsString>,
+    /// Height map file (*.pgm, *.png or *.csv). Images are scaled by --height
+    #[arg(long)]
+    height_map: Option<OsString>,
+    /// Physical size of the height map as "W,H" (defaults to the model footprint)
+    #[arg(long, value_parser = parse_vector)]
+    height_map_size: Option<Vector3<f64>>,
+    #[arg(long, value_enum, default_value_t = DEFAULT_INTERPOLATION)]
+    interpolation: Interpolation,
+    /// Profile file of revolved transform (*.csv or *.json) consisting of (r, offset) pairs
+    #[arg(long)]
+    profile: Option<OsString>,
+    #[arg(long, value_enum, default_value_t = DEFAULT_PROFILE_INTERPOLATION)]
+    profile_interpolation: ProfileInterpolation,
+    /// Limit the transform to a circle given as "X,Y,R"
+    #[arg(long, value_parser = parse_circle, conflicts_with = "mask_polygon")]
+    mask_circle: Option<Region>,
+    /// Limit the transform to a polygon given as "X1,Y1;X2,Y2;X3,Y3;..."
+    #[arg(long)]
+    mask_polygon: Option<String>,
+    /// Width of the band outside the mask where the offset fades out
+    #[arg(long, default_value_t = DEFAULT_MASK_FALLOFF)]
+    mask_falloff: f64,
+    #[arg(long, default_value_t = DEFAULT_SHEAR_ANGLE)]
+    shear_angle: f64,
+    /// Direction of shear, counterclockwise from X axis in degrees
+    #[arg(long, default_value_t = DEFAULT_SHEAR_DIRECTION)]
+    shear_direction: f64,
+    /// Rotation of twist transform per mm of height in degrees
+    #[arg(long, default_value_t = DEFAULT_TWIST_RATE)]
+    twist_rate: f64,
+    /// Radial scaling of radial-stretch transform per mm of height
+    #[arg(long, default_value_t = DEFAULT_STRETCH_RATE)]
+    stretch_rate: f64,
+    /// Amplitude of noise transform (the offset varies between 0 and twice of this)
+    #[arg(long, default_value_t = DEFAULT_NOISE_AMPLITUDE)]
+    noise_amplitude: f64,
+    /// Size of the largest features of noise transform
+    #[arg(long, default_value_t = DEFAULT_NOISE_SCALE)]
+    noise_scale: f64,
+    /// Number of octaves of noise transform, each of them half the size of the previous one
+    #[arg(long, default_value_t = DEFAULT_NOISE_OCTAVES)]
+    noise_octaves: u32,
+    #[arg(long, default_value_t = DEFAULT_NOISE_SEED)]
+    noise_seed: u64,
+    /// How much the layers follow the top surface of the model in conformal transform (1 = fully)
+    #[arg(long, default_value_t = DEFAULT_CONFORMAL_STRENGTH)]
+    conformal_strength: f64,
+    /// Offset expression of x and y (e.g. "0.5*sin(x/3)+0.1*hypot(x,y)")
+    #[arg(long)]
+    offset: Option<String>,
+    /// Standard deviation of Gaussian blur applied to the offset field in mm
+    /// [default: 2 for conformal transform, otherwise 0]
+    #[arg(long)]
+    smooth: Option<f64>,
+    /// Maximum slope of the offset field in degrees. Steeper peaks are cut down
+    #[arg(long)]
+    max_slope: Option<f64>,
+    /// Maximum curvature of the offset field in 1/mm. The field is blurred until it is below this
+    #[arg(long)]
+    max_curvature: Option<f64>,
+    /// Choose the slope angle of conical transform so that overhangs become printable
+    #[arg(long)]
+    auto_slope: bool,
+    /// Steepest printable overhang for --auto-slope in degrees, measured from the normal of the layer surface
+    /// (0 = vertical wall, 90 = ceiling)
+    #[arg(long, default_value_t = DEFAULT_PRINTABLE_ANGLE)]
+    printable_angle: f64,
+    /// Clearance angle of the nozzle (and heater block) in degrees.
+    /// If given, the slopes of the layer surfaces are checked against it
+    #[arg(long)]
+    clearance_angle: Option<f64>,
+    /// Whether to warn or fail when the layers are steeper than the clearance angle
+    #[arg(long, value_enum, default_value_t = DEFAULT_CLEARANCE_ACTION)]
+    clearance_action: ClearanceAction,
 }
 
 pub fn command_main(args: WarpArgs) -> Result<()> {
     let input_path = Path::new(&args.input_file);
     let input_mesh = stl_io::read_stl(&mut File::open(input_path)?)?.into();
     let Aabb { origin, size } = calc_aabb(&input_mesh);
-    let center = args.center.unwrap_or(vector![
-        origin.x + size.x / 2.0,
-        origin.y + size.y / 2.0,
-        origin.z
-    ]);
-
-    let transform = match args.transform_type {
-        TransformType::Conical => {
-            // TODO:
-            if args.slope_angle < 0.0 && args.flat_bottom != 0.0 {
-                panic!("Flat bottom is not supported for negative slope angle");
-            }
-            Transform::Conical {
-                slope_angle: args.slope_angle * std::f64::consts::PI / 180.0,
-                flat_bottom: args.flat_bottom,
-            }
+    let default_center = vector![origin.x + size.x / 2.0, origin.y + size.y / 2.0, origin.z];
+    let center = match args.center.as_slice() {
+        [center] => *center,
+        _ => default_center,
+    };
+
+    let mut transform = match &args.transform_json {
+        Some(path) => {
+            let transform: Transform = serde_json::from_reader(File::open(path)?)?;
+            transform.validate()?;
+            transform
         }
-        TransformType::Sinusoidal => Transform::Sinusoidal {
-            height: args.height,
-            pitch: args.pitch,
-            flat_bottom: args.flat_bottom,
-        },
-        TransformType::Spherical => {
-            // TODO:
-            if args.radius < 0.0 {
-                panic!("Only positive radius is supported");
-            }
-            Transform::Spherical {
-                radius: args.radius,
-                flat_bottom: args.flat_bottom,
-            }
+        None => transform_from_args(&args, &input_mesh, center, Aabb { origin, size })?,
+    };
+
+    let auto_slope_angle = if args.auto_slope {
+        ensure!(
+            args.transform_json.is_none() && args.transform_types.contains(&TransformType::Conical),
+            "--auto-slope requires conical transform"
+        );
+        // Overhangs are analyzed with the transform before smoothing
+        ensure!(
+            args.smooth.is_none() && args.max_slope.is_none() && args.max_curvature.is_none(),
+            "--auto-slope cannot be combined with --smooth, --max-slope or --max-curvature"
+        );
+        // Layers steeper than the nozzle clearance are not worth trying
+        let max_angle = args
+            .clearance_angle
+            .unwrap_or(AUTO_SLOPE_MAX)
+            .min(AUTO_SLOPE_MAX);
+        let (slope_angle, overhang, location) = auto_slope(
+            &mut transform,
+            &input_mesh,
+            center,
+            args.printable_angle,
+            max_angle,
+        );
+        if overhang > args.printable_angle + OVERHANG_TOLERANCE {
+            eprintln!(
+                "Warning: No slope angle makes all overhangs printable. Overhang of {:.1} degrees remains at X{:.3} Y{:.3} Z{:.3}",
+                overhang, location.x, location.y, location.z
+            );
         }
+        println!("Slope angle: {:.1} degrees", slope_angle);
+        Some(slope_angle.to_radians())
+    } else {
+        None
+    };
+
+    // The envelope of conformal transform is smoothed when it is created, instead of the offset field
+    let smoothing = smoothing(&args, 0.0)?;
+    let conformal =
+        args.transform_json.is_none() && args.transform_types.contains(&TransformType::Conformal);
+    let transform = if !conformal
+        && (smoothing.sigma > 0.0
+            || smoothing.max_slope.is_some()
+            || smoothing.max_curvature.is_some())
+    {
+        // Cover the footprint of the model, with a margin for the blur
+        let margin = 3.0 * smoothing.sigma + args.max_edge_len;
+        let extent = footprint_extent(center, Aabb { origin, size }, margin);
+        transform.smoothed(&smoothing, extent, args.max_edge_len)?
+    } else {
+        transform
     };
 
+    check_monotonic(&transform, center, Aabb { origin, size }, args.max_edge_len)?;
+
+    if let Some(clearance_angle) = args.clearance_angle {
+        let (slope, location) =
+            max_slope(&transform, center, Aabb { origin, size }, args.max_edge_len)?;
+        if slope > clearance_angle {
+            let message = format!(
+                "Layer surface is inclined by {:.1} degrees at X{:.3} Y{:.3} Z{:.3}, exceeding the clearance angle {:.1} degrees",
+                slope, location.x, location.y, location.z, clearance_angle
+            );
+            match args.clearance_action {
+                ClearanceAction::Warn => eprintln!("Warning: {}", message),
+                ClearanceAction::Fail => bail!(message),
+            }
+        }
+    }
+
     let tesselated_mesh = tesselate(input_mesh, args.max_edge_len);
 
-    let warped_mesh = warp_mesh(tesselated_mesh, transform, center);
+    let warped_mesh = warp_mesh(tesselated_mesh, &transform, center);
 
     let warped_aabb = calc_aabb(&warped_mesh);
 
@@ -110,12 +334,484 @@ pub fn command_main(args: WarpArgs) -> Result<()> {
         &TransformData {
             transform,
             warped_aabb,
+            center: Some(center),
+            original_aabb: Some(Aabb { origin, size }),
+            auto_slope_angle,
         },
     )?;
 
     Ok(())
 }
 
+/// Create a transform from command line options
+fn transform_from_args(
+    args: &WarpArgs,
+    mesh: &Mesh,
+    center: Vector3<f64>,
+    aabb: Aabb,
+) -> Result<Transform> {
+    let Aabb { origin, size } = aabb;
+
+    let blend = Blend::new(args.flat_bottom, args.blend_profile)
+        .with_flat_top(args.flat_top, origin.z + size.z - center.z);
+
+    // Mask is given in the model coordinates
+    let region = match (&args.mask_circle, &args.mask_polygon) {
+        (Some(circle), _) => Some(circle.clone()),
+        (None, Some(polygon)) => Some(Region::Polygon(parse_polygon(polygon)?)),
+        (None, None) => None,
+    };
+    let mask = region.map(|region| Mask {
+        region: region.translate(-center.xy()),
+        falloff: args.mask_falloff,
+    });
+
+    // When multiple centers are given, each of them has its own copy of the transform,
+    // limited to its region of influence
+    let sub_centers: Vec<Vector2<f64>> = if args.center.len() > 1 {
+        args.center.iter().map(|c| c.xy() - center.xy()).collect()
+    } else {
+        vec![Vector2::zeros()]
+    };
+    let influence_radius = args.influence_radius.unwrap_or_else(|| {
+        // Half of the distance to the nearest center, leaving room for the falloff
+        // so that the neighboring regions do not overlap
+        let min_distance = sub_centers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, a)| sub_centers[i + 1..].iter().map(move |b| (b - a).norm()))
+            .fold(f64::MAX, f64::min);
+        (min_distance / 2.0 - args.mask_falloff).max(0.0)
+    });
+
+    let mut transforms = Vec::new();
+    for &transform_type in args.transform_types.iter() {
+        let transform = create_transform(transform_type, args, blend, mesh, center, aabb)?;
+        ensure!(
+            !transform.moves_xy() || (mask.is_none() && sub_centers.len() == 1),
+            "{:?} transform cannot be combined with a mask or multiple centers",
+            transform_type
+        );
+        // The envelope is the top surface of the whole model
+        ensure!(
+            transform_type != TransformType::Conformal || sub_centers.len() == 1,
+            "Conformal transform cannot be combined with multiple centers"
+        );
+
+        for &sub_center in sub_centers.iter() {
+            let mut transform = transform.clone();
+            if sub_centers.len() > 1 {
+                transform = Transform::Centered {
+                    center: sub_center,
+                    transform: Box::new(Transform::Masked {
+                        mask: Mask {
+                            region: Region::Circle {
+                                center: Vector2::zeros(),
+                                radius: influence_radius,
+                            },
+                            falloff: args.mask_falloff,
+                        },
+                        transform: Box::new(transform),
+                    }),
+                };
+            }
+            if let Some(mask) = &mask {
+                transform = Transform::Masked {
+                    mask: mask.clone(),
+                    transform: Box::new(transform),
+                };
+            }
+            transforms.push(transform);
+        }
+    }
+
+    Ok(if transforms.len() == 1 {
+        transforms.remove(0)
+    } else {
+        Transform::Compose(transforms)
+    })
+}
+
+fn create_transform(
+    transform_type: TransformType,
+    args: &WarpArgs,
+    blend: Blend,
+    mesh: &Mesh,
+    center: Vector3<f64>,
+    aabb: Aabb,
+) -> Result<Transform> {
+    let model_size = aabb.size;
+    // Transforms which move X and Y have no offset to blend
+    ensure!(
+        !matches!(
+            transform_type,
+            TransformType::Twist | TransformType::RadialStretch | TransformType::Bend
+        ) || (args.flat_bottom == 0.0 && args.flat_top == 0.0),
+        "--flat-bottom and --flat-top are not supported for twist, radial-stretch and bend transforms"
+    );
+    Ok(match transform_type {
+        TransformType::Conical => Transform::Conical {
+            slope_angle: args.slope_angle * std::f64::consts::PI / 180.0,
+            apex_radius: args.apex_radius,
+            anisotropy: anisotropy(args)?,
+            blend,
+        },
+        TransformType::Sinusoidal => {
+            let wave = Wave {
+                height: args.height,
+                pitch: args.pitch,
+                pitch_y: args.pitch_y,
+                phase: args.phase.to_radians(),
+                rotation: args.wave_rotation.to_radians(),
+                phase_shift: args.phase_shift.to_radians(),
+                waveform: args.waveform,
+                corrugation: args.corrugation,
+            };
+            // Otherwise some layers would be folded over even without blending.
+            // Blending is checked with the other transforms before warping.
+            ensure!(
+                wave.max_z_deriv() < 1.0,
+                "--phase-shift is too large for the height and the waveform"
+            );
+            Transform::Sinusoidal { wave, blend }
+        }
+        TransformType::Spherical => Transform::Spherical {
+            radius: args.radius,
+            anisotropy: anisotropy(args)?,
+            blend,
+        },
+        TransformType::Revolved => {
+            let path = args
+                .profile
+                .as_ref()
+                .context("--profile is required for revolved transform")?;
+            Transform::Revolved {
+                profile: Profile::load(Path::new(path), args.profile_interpolation)?,
+                blend,
+            }
+        }
+        TransformType::HeightMap => {
+            let path = args
+                .height_map
+                .as_ref()
+                .context("--height-map is required for height-map transform")?;
+            let size = args.height_map_size.unwrap_or(model_size);
+            Transform::HeightMap {
+                map: HeightMap::load(Path::new(path), size.xy(), args.height, args.interpolation)?,
+                blend,
+            }
+        }
+        TransformType::Conformal => {
+            ensure!(
+                args.conformal_strength >= 0.0,
+                "--conformal-strength must not be negative"
+            );
+            let mut envelope = HeightMap::upper_envelope(
+                mesh,
+                center,
+                footprint_extent(center, aabb, args.max_edge_len),
+                args.max_edge_len,
+                Interpolation::Bilinear,
+            )?;
+            // The layers follow the smoothed top surface
+            smoothing(args, DEFAULT_CONFORMAL_SMOOTH)?.apply(&mut envelope)?;
+            let height = envelope.values.iter().copied().fold(f64::MIN, f64::max);
+            Transform::Conformal {
+                envelope,
+                height,
+                strength: args.conformal_strength,
+                blend,
+            }
+        }
+        TransformType::Expr => Transform::Expr {
+            offset: Expr::parse(
+                args.offset
+                    .as_ref()
+                    .context("--offset is required for expr transform")?,
+            )?,
+            blend,
+        },
+        TransformType::Shear => Transform::Shear {
+            angle: args.shear_angle.to_radians(),
+            direction: args.shear_direction.to_radians(),
+        },
+        TransformType::Tilt => Transform::Tilt {
+            angle: args.tilt_angle.to_radians(),
+            direction: args.tilt_direction.to_radians(),
+            blend,
+        },
+        TransformType::Noise => {
+            ensure!(args.noise_scale > 0.0, "--noise-scale must be positive");
+            Transform::Noise {
+                noise: Noise::new(NoiseParams {
+                    amplitude: args.noise_amplitude,
+                    scale: args.noise_scale,
+                    octaves: args.noise_octaves,
+                    seed: args.noise_seed,
+                }),
+                blend,
+            }
+        }
+        TransformType::Twist => Transform::Twist {
+            rate: args.twist_rate.to_radians(),
+        },
+        TransformType::RadialStretch => {
+            ensure!(
+                1.0 + args.stretch_rate * model_size.z > 0.0,
+                "--stretch-rate shrinks the model to a point below its top"
+            );
+            Transform::RadialStretch {
+                rate: args.stretch_rate,
+            }
+        }
+        TransformType::Bend => {
+            ensure!(
+                1.0 + model_size.z / args.radius > 0.0,
+                "--radius is too small to bend the model downward"
+            );
+            ensure!(
+                model_size.x / 2.0 < PI / 2.0 * args.radius.abs(),
+                "--radius is too small for the width of the model"
+            );
+            Transform::Bend {
+                radius: args.radius,
+            }
+        }
+    })
+}
+
+/// Smallest slope angle of conical transforms (in degrees, either positive or negative)
+/// which brings all overhangs of the mesh within `printable_angle`.
+/// If there is no such angle, the one with the least steep overhang is chosen.
+/// The transform is updated with the angle, and the remaining steepest overhang and its location are also returned.
+fn auto_slope(
+    transform: &mut Transform,
+    mesh: &Mesh,
+    center: Vector3<f64>,
+    printable_angle: f64,
+    max_angle: f64,
+) -> (f64, f64, Vector3<f64>) {
+    let bottom = mesh
+        .vertices
+        .iter()
+        .map(|v| v.z)
+        .fold(f64::INFINITY, f64::min);
+
+    // Vertices and centroids of the faces which are not on the bed, with the unit normals
+    let samples: Vec<(Vector3<f64>, Vector3<f64>)> = mesh
+        .triangles
+        .iter()
+        .filter_map(|triangle| {
+            let [a, b, c] = triangle.map(|i| mesh.vertices[i]);
+            if [a, b, c].iter().all(|p| p.z < bottom + BED_TOLERANCE) {
+                return None;
+            }
+            let normal = (b - a).cross(&(c - a)).try_normalize(0.0)?;
+            Some([a, b, c, (a + b + c) / 3.0].map(|p| (p, normal)))
+        })
+        .flatten()
+        .collect();
+
+    // 0, +step, -step, +2*step, -2*step, ...
+    let steps = (max_angle / AUTO_SLOPE_STEP).floor() as usize;
+    let candidates = std::iter::once(0.0).chain((1..=steps).flat_map(|k| {
+        let angle = k as f64 * AUTO_SLOPE_STEP;
+        [angle, -angle]
+    }));
+
+    let mut best = (0.0, f64::INFINITY, center);
+    for angle in candidates {
+        set_slope_angle(transform, angle.to_radians());
+        let (overhang, location) = steepest_overhang(transform, &samples, center);
+        if overhang < best.1 {
+            best = (angle, overhang, location);
+        }
+        if overhang <= printable_angle + OVERHANG_TOLERANCE {
+            break;
+        }
+    }
+
+    set_slope_angle(transform, best.0.to_radians());
+    best
+}
+
+fn set_slope_angle(transform: &mut Transform, angle: f64) {
+    match transform {
+        Transform::Conical { slope_angle, .. } => *slope_angle = angle,
+        Transform::Masked { transform, .. } | Transform::Centered { transform, .. } => {
+            set_slope_angle(transform, angle)
+        }
+        Transform::Compose(transforms) => {
+            for transform in transforms.iter_mut() {
+                set_slope_angle(transform, angle);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Steepest overhang (in degrees from the normal of the layer surface) among the points on faces
+/// with the given normals, and its location.
+/// Layer surfaces are the level sets of the warped Z, which are sliced flat.
+fn steepest_overhang(
+    transform: &Transform,
+    samples: &[(Vector3<f64>, Vector3<f64>)],
+    center: Vector3<f64>,
+) -> (f64, Vector3<f64>) {
+    let h = LAYER_NORMAL_STEP;
+    let mut steepest = (0.0, center);
+    for &(location, normal) in samples {
+        let point = location - center;
+        let warped_z = |d: Vector3<f64>| transform.apply(point + d).z;
+        let gradient = vector![
+            warped_z(vector![h, 0.0, 0.0]) - warped_z(vector![-h, 0.0, 0.0]),
+            warped_z(vector![0.0, h, 0.0]) - warped_z(vector![0.0, -h, 0.0]),
+            warped_z(vector![0.0, 0.0, h]) - warped_z(vector![0.0, 0.0, -h])
+        ];
+        let Some(layer_normal) = gradient.try_normalize(0.0) else {
+            continue;
+        };
+
+        // Facing down against the layer normal
+        let overhang = (-normal.dot(&layer_normal))
+            .clamp(-1.0, 1.0)
+            .asin()
+            .to_degrees();
+        // NaN (e.g. outside a sphere) is ignored
+        if overhang > steepest.0 {
+            steepest = (overhang, location);
+        }
+    }
+    steepest
+}
+
+/// Half size of the XY area relative to the center which covers the model with a margin
+fn footprint_extent(center: Vector3<f64>, aabb: Aabb, margin: f64) -> Vector2<f64> {
+    let Aabb { origin, size } = aabb;
+    vector![
+        (origin.x - center.x)
+            .abs()
+            .max(origin.x + size.x - center.x),
+        (origin.y - center.y)
+            .abs()
+            .max(origin.y + size.y - center.y)
+    ]
+    .add_scalar(margin)
+}
+
+/// Points on a grid over the AABB, spaced by `step`.
+/// The spacing is widened when there would be more than `MAX_GRID_SAMPLES` points.
+fn sample_grid(aabb: Aabb, step: f64) -> impl Iterator<Item = Vector3<f64>> {
+    let Aabb { origin, size } = aabb;
+    let count = |step: f64| size.map(|length| (length / step).ceil() as usize + 1);
+
+    let samples = count(step).product();
+    let step = if samples > MAX_GRID_SAMPLES {
+        step * (samples as f64 / MAX_GRID_SAMPLES as f64).cbrt()
+    } else {
+        step
+    };
+
+    let counts = count(step);
+    (0..counts.z).flat_map(move |k| {
+        (0..counts.y).flat_map(move |j| {
+            (0..counts.x)
+                .map(move |i| origin + (step * vector![i as f64, j as f64, k as f64]).inf(&size))
+        })
+    })
+}
+
+/// Ensure that the transform is invertible over the model,
+/// i.e. the warped Z increases with Z so that no layers are folded over
+fn check_monotonic(
+    transform: &Transform,
+    center: Vector3<f64>,
+    aabb: Aabb,
+    step: f64,
+) -> Result<()> {
+    for point in sample_grid(aabb, step) {
+        let jacobian = transform.jacobian(point - center);
+        ensure!(
+            !jacobian.is_nan(),
+            "Layer surface is undefined at X{:.3} Y{:.3} Z{:.3} (e.g. outside the sphere of spherical transform)",
+            point.x,
+            point.y,
+            point.z
+        );
+        ensure!(
+            jacobian > 0.0,
+            "Layers are folded over at X{:.3} Y{:.3} Z{:.3}: the warped Z must increase with Z",
+            point.x,
+            point.y,
+            point.z
+        );
+    }
+    Ok(())
+}
+
+/// Steepest slope (in degrees) of the layer surfaces over the footprint of the model,
+/// and where it is (in the model coordinates)
+fn max_slope(
+    transform: &Transform,
+    center: Vector3<f64>,
+    aabb: Aabb,
+    step: f64,
+) -> Result<(f64, Vector3<f64>)> {
+    // Layers change with height due to blending even if the offset does not depend on z
+    let mut max = (0.0, aabb.origin);
+    for point in sample_grid(aabb, step) {
+        let gradient = transform.layer_gradient(point - center);
+        let slope = gradient.norm().atan().to_degrees();
+        ensure!(
+            !slope.is_nan(),
+            "Layer surface is undefined at X{:.3} Y{:.3} Z{:.3}",
+            point.x,
+            point.y,
+            point.z
+        );
+        if slope > max.0 {
+            max = (slope, point);
+        }
+    }
+
+    Ok(max)
+}
+
+/// Smoothing options, with `default_sigma` when --smooth is not given
+fn smoothing(args: &WarpArgs, default_sigma: f64) -> Result<Smoothing> {
+    let smoothing = Smoothing {
+        sigma: args.smooth.unwrap_or(default_sigma),
+        max_slope: args.max_slope.map(f64::to_radians),
+        max_curvature: args.max_curvature,
+    };
+    ensure!(smoothing.sigma >= 0.0, "Smoothing must not be negative");
+    ensure!(
+        smoothing
+            .max_slope
+            .is_none_or(|angle| angle > 0.0 && angle < PI / 2.0),
+        "Max slope must be between 0 and 90 degrees"
+    );
+    ensure!(
+        smoothing
+            .max_curvature
+            .is_none_or(|curvature| curvature > 0.0),
+        "Max curvature must be positive"
+    );
+    Ok(smoothing)
+}
+
+fn anisotropy(args: &WarpArgs) -> Result<Anisotropy> {
+    ensure!(
+        args.scale_x > 0.0 && args.scale_y > 0.0,
+        "--scale-x and --scale-y must be positive"
+    );
+    Ok(Anisotropy {
+        scale_x: args.scale_x,
+        scale_y: args.scale_y,
+        rotation: args.anisotropy_rotation.to_radians(),
+    })
+}
+
 fn unindex_stl(mesh: IndexedMesh) -> Vec<Triangle> {
     mesh.faces
         .iter()
@@ -127,8 +823,8 @@ fn unindex_stl(mesh: IndexedMesh) -> Vec<Triangle> {
 }
 
 fn calc_aabb(input: &Mesh) -> Aabb {
-    let mut min = Vector3::from_element(std::f64::MAX);
-    let mut max = Vector3::from_element(std::f64::MIN);
+    let mut min = Vector3::from_element(f64::MAX);
+    let mut max = Vector3::from_element(f64::MIN);
 
     for vert in input.vertices.iter() {
         min = min.map_with_location(|i, _, e: f64| e.min(vert[i]));
@@ -141,7 +837,7 @@ fn calc_aabb(input: &Mesh) -> Aabb {
     }
 }
 
-fn warp_mesh(input: Mesh, transform: Transform, center: Vector3<f64>) -> Mesh {
+fn warp_mesh(input: Mesh, transform: &Transform, center: Vector3<f64>) -> Mesh {
     let vertices = input
         .vertices
         .into_iter()
@@ -153,3 +849,115 @@ fn warp_mesh(input: Mesh, transform: Transform, center: Vector3<f64>) -> Mesh {
         triangles: input.triangles,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Closed box with outward normals
+    fn cuboid(min: Vector3<f64>, max: Vector3<f64>) -> Mesh {
+        // Bits of the index select max (1) or min (0) of X, Y and Z
+        let vertices = (0..8)
+            .map(|i| {
+                vector![
+                    if i & 1 == 0 { min.x } else { max.x },
+                    if i & 2 == 0 { min.y } else { max.y },
+                    if i & 4 == 0 { min.z } else { max.z }
+                ]
+            })
+            .collect();
+        let triangles = vec![
+            [0, 2, 3],
+            [0, 3, 1],
+            [4, 5, 7],
+            [4, 7, 6],
+            [0, 1, 5],
+            [0, 5, 4],
+            [2, 6, 7],
+            [2, 7, 3],
+            [0, 4, 6],
+            [0, 6, 2],
+            [1, 3, 7],
+            [1, 7, 5],
+        ];
+        Mesh {
+            vertices,
+            triangles,
+        }
+    }
+
+    fn conical() -> Transform {
+        Transform::Conical {
+            slope_angle: 0.0,
+            apex_radius: 0.0,
+            anisotropy: Anisotropy::default(),
+            blend: Blend::new(0.0, BlendProfile::Linear),
+        }
+    }
+
+    #[test]
+    fn auto_slope() {
+        // Walls are printable with planar layers
+        let cube = cuboid(vector![-5.0, -5.0, 0.0], vector![5.0, 5.0, 10.0]);
+        let mut transform = conical();
+        let (angle, overhang, _) = super::auto_slope(
+            &mut transform,
+            &cube,
+            Vector3::zeros(),
+            DEFAULT_PRINTABLE_ANGLE,
+            AUTO_SLOPE_MAX,
+        );
+        assert_eq!(angle, 0.0);
+        assert!(overhang.abs() < 1e-6);
+
+        // Horizontal underside of the arms is printable when the layers are inclined by 45 degrees
+        let stem = cuboid(vector![-2.0, -2.0, 0.0], vector![2.0, 2.0, 10.0]);
+        let arms = cuboid(vector![-10.0, -2.0, 10.0], vector![10.0, 2.0, 14.0]);
+        let t_shape = Mesh {
+            vertices: [stem.vertices, arms.vertices].concat(),
+            triangles: [
+                stem.triangles,
+                arms.triangles.iter().map(|t| t.map(|i| i + 8)).collect(),
+            ]
+            .concat(),
+        };
+        let mut transform = conical();
+        let (angle, overhang, _) = super::auto_slope(
+            &mut transform,
+            &t_shape,
+            Vector3::zeros(),
+            DEFAULT_PRINTABLE_ANGLE,
+            AUTO_SLOPE_MAX,
+        );
+        assert_eq!(angle.abs(), 45.0);
+        assert!((overhang - 45.0).abs() < OVERHANG_TOLERANCE);
+        let Transform::Conical { slope_angle, .. } = transform else {
+            unreachable!()
+        };
+        assert_eq!(slope_angle, angle.to_radians());
+    }
+    #[test]
+    fn max_slope() {
+        // Layers follow the top surface sloping by 1/4 along X, and become flatter below it
+        let envelope =
+            HeightMap::sampled(vector![10.0, 5.0], 1.0, Interpolation::Bilinear, |x, _| {
+                5.0 + x / 4.0
+            })
+            .unwrap();
+        let transform = Transform::Conformal {
+            envelope,
+            height: 7.5,
+            strength: 1.0,
+            blend: Blend::new(0.0, BlendProfile::Linear),
+        };
+        let aabb = Aabb {
+            origin: vector![-2.0, -1.0, 0.0],
+            size: vector![4.0, 2.0, 4.5],
+        };
+
+        let (slope, location) =
+            super::max_slope(&transform, Vector3::zeros(), aabb, DEFAULT_MAX_EDGE_LEN).unwrap();
+        assert!((slope - 0.25f64.atan().to_degrees()).abs() < 1e-3);
+        assert_eq!((location.x, location.z), (-2.0, 4.5));
+    }
+}
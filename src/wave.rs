@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodic offset patterns (sinusoidal and zigzag slicing).
+//!
+//! In the pattern coordinates (u, v), which are rotated by `rotation` from (x, y),
+//!   offset = height*(w(u/pitch + (phase + phase_shift*z)/2π)*w(v/pitch_y + 1/4) + 1)/2
+//! where w is a waveform with period 1 and range [-1, 1].
+//! With `corrugation`, the pattern varies only along u:
+//!   offset = height*(w(u/pitch + (phase + phase_shift*z)/2π) + 1)/2
+
+use std::f64::consts::PI;
+
+use clap::ValueEnum;
+use na::{vector, Vector2};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, ValueEnum)]
+pub enum Waveform {
+    /// sin(2πt)
+    #[default]
+    Sine,
+    /// Zigzag with the same phase as the sine
+    Triangle,
+    /// Triangle wave doubled and clipped, so that half of the period is flat
+    Trapezoid,
+}
+
+impl Waveform {
+    /// Value and derivative at t (in periods)
+    fn eval(&self, t: f64) -> (f64, f64) {
+        match self {
+            Waveform::Sine => ((2.0 * PI * t).sin(), 2.0 * PI * (2.0 * PI * t).cos()),
+            Waveform::Triangle => {
+                // Distance from the peak at t = 1/4
+                let u = (t + 0.25).rem_euclid(1.0) - 0.5;
+                (1.0 - 4.0 * u.abs(), -4.0 * u.signum())
+            }
+            Waveform::Trapezoid => {
+                let (value, deriv) = Waveform::Triangle.eval(t);
+                if value.abs() < 0.5 {
+                    (2.0 * value, 2.0 * deriv)
+                } else {
+                    (value.signum(), 0.0)
+                }
+            }
+        }
+    }
+
+    /// Maximum of the absolute value of the derivative
+    fn max_deriv(&self) -> f64 {
+        match self {
+            Waveform::Sine => 2.0 * PI,
+            Waveform::Triangle => 4.0,
+            Waveform::Trapezoid => 8.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Wave {
+    /// Peak-to-peak height
+    pub height: f64,
+    /// Pitch along the (rotated) X axis
+    pub pitch: f64,
+    /// Pitch along the (rotated) Y axis. Same as `pitch` if not given
+    #[serde(default)]
+    pub pitch_y: Option<f64>,
+    /// Phase along the X axis (radians)
+    #[serde(default)]
+    pub phase: f64,
+    /// Rotation of the pattern in XY plane, counterclockwise (radians)
+    #[serde(default)]
+    pub rotation: f64,
+    /// Change of phase per unit height (radians/mm), e.g. for interlocking layers
+    #[serde(default)]
+    pub phase_shift: f64,
+    #[serde(default)]
+    pub waveform: Waveform,
+    /// Vary only along the X axis
+    #[serde(default)]
+    pub corrugation: bool,
+}
+
+impl Wave {
+    pub fn offset(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.eval(x, y, z).0
+    }
+
+    pub fn gradient(&self, x: f64, y: f64, z: f64) -> Vector2<f64> {
+        self.eval(x, y, z).1
+    }
+
+    /// Derivative of offset respect to z
+    pub fn z_deriv(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.eval(x, y, z).2
+    }
+
+    /// Upper bound of |∂offset/∂z|. The transform is invertible only when this is less than 1.
+    pub fn max_z_deriv(&self) -> f64 {
+        self.height.abs() / 2.0 * self.waveform.max_deriv() * self.phase_shift.abs() / (2.0 * PI)
+    }
+
+    /// Offset, its gradient in XY plane and its derivative respect to z
+    fn eval(&self, x: f64, y: f64, z: f64) -> (f64, Vector2<f64>, f64) {
+        let (sin, cos) = self.rotation.sin_cos();
+        let (u, v) = (cos * x + sin * y, -sin * x + cos * y);
+        let pitch_y = self.pitch_y.unwrap_or(self.pitch);
+
+        let phase = self.phase + self.phase_shift * z;
+        let (wu, dwu) = self.waveform.eval(u / self.pitch + phase / (2.0 * PI));
+        let (wv, dwv) = if self.corrugation {
+            (1.0, 0.0)
+        } else {
+            self.waveform.eval(v / pitch_y + 0.25)
+        };
+
+        let offset = self.height * (wu * wv + 1.0) / 2.0;
+        let gradient_uv = self.height / 2.0 * vector![dwu * wv / self.pitch, wu * dwv / pitch_y];
+        // Rotate back to XY
+        let gradient = vector![
+            cos * gradient_uv.x - sin * gradient_uv.y,
+            sin * gradient_uv.x + cos * gradient_uv.y
+        ];
+
+        let z_deriv = self.height / 2.0 * dwu * wv * self.phase_shift / (2.0 * PI);
+
+        (offset, gradient, z_deriv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradient() {
+        for waveform in Waveform::value_variants() {
+            for corrugation in [false, true] {
+                let wave = Wave {
+                    height: 2.0,
+                    pitch: 7.0,
+                    pitch_y: Some(4.0),
+                    phase: 0.3,
+                    rotation: PI / 5.0,
+                    phase_shift: 0.2,
+                    waveform: *waveform,
+                    corrugation,
+                };
+
+                let (x, y, z) = (1.3, -2.1, 0.7);
+                let offset = wave.offset(x, y, z);
+                assert!((0.0..=2.0).contains(&offset));
+
+                let h = 1e-6;
+                let numerical = vector![
+                    (wave.offset(x + h, y, z) - wave.offset(x - h, y, z)) / (2.0 * h),
+                    (wave.offset(x, y + h, z) - wave.offset(x, y - h, z)) / (2.0 * h)
+                ];
+                assert!((wave.gradient(x, y, z) - numerical).norm() < 1e-6);
+
+                let numerical = (wave.offset(x, y, z + h) - wave.offset(x, y, z - h)) / (2.0 * h);
+                assert!((wave.z_deriv(x, y, z) - numerical).abs() < 1e-6);
+                assert!(wave.z_deriv(x, y, z).abs() <= wave.max_z_deriv());
+            }
+        }
+    }
+
+    #[test]
+    fn triangle() {
+        let wave = Wave {
+            height: 2.0,
+            pitch: 4.0,
+            pitch_y: None,
+            phase: 0.0,
+            rotation: 0.0,
+            phase_shift: 0.0,
+            waveform: Waveform::Triangle,
+            corrugation: true,
+        };
+
+        assert_eq!(wave.offset(0.0, 0.0, 0.0), 1.0);
+        assert_eq!(wave.offset(1.0, 5.0, 0.0), 2.0);
+        assert_eq!(wave.offset(0.5, 0.0, 0.0), 1.5);
+        assert_eq!(wave.offset(-1.0, 0.0, 0.0), 0.0);
+    }
+}